@@ -1,12 +1,13 @@
 use std::{collections::HashMap, env, error::Error};
 
 use groq_client_rs::chat::*;
-use serde_json::json;
+
+type ToolFn = fn(String) -> Result<String, Box<dyn Error + Send + Sync>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
-    let mut function_map: HashMap<String, fn(String) -> Result<String, Box<dyn Error + Send + Sync>>> = HashMap::new();
+    let mut function_map: HashMap<String, ToolFn> = HashMap::new();
     function_map.insert("run_tool1".to_string(), run_tool1);
     function_map.insert("run_tool2".to_string(), run_tool2);
 
@@ -45,12 +46,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         function: Function {
             name: Some("configure_cisco_switch".to_string()),
             description: Some("Sends a configuration to a Cisco Nexus 9000 switch".to_string()),
-            parameters: Some(json!({
-                "configuration": {
-                    "type": "string",
-                    "description": "The configuration to send to the switch"
-                }
-            })),
+            parameters: Some(ParametersBuilder::new()
+                .add_string_param("configuration", "The configuration to send to the switch", true)
+                .build()),
         },
         tool_type: ToolType::Function,
     });
@@ -59,12 +57,9 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         function: Function {
             name: Some("configure_juniper_switch".to_string()),
             description: Some("Sends a configuration to a Juniper QFX switch".to_string()),
-            parameters: Some(json!({
-                "configuration": {  
-                    "type": "string",
-                    "description": "The configuration to send to the switch"
-                }
-            })),
+            parameters: Some(ParametersBuilder::new()
+                .add_string_param("configuration", "The configuration to send to the switch", true)
+                .build()),
         },
         tool_type: ToolType::Function,
     });
@@ -0,0 +1,81 @@
+use std::{env, error::Error};
+
+use groq_client_rs::chat::*;
+use groq_client_rs::error::GroqError;
+use serde_json::Value;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+
+    let api_key = env::var("GROQ_API_KEY").expect("GROQ_API_KEY is not set");
+    let mut chat = Chat::new(
+        api_key,
+        "deepseek-r1-distill-llama-70b".to_string(),
+    );
+
+    chat.add_chat_message(ChatMessage::new(
+        ChatRole::System,
+        r#"You are a network automation expert.
+        You generate network configuration commands to achieve a given goal.
+        The network configurations have to match the network device type.
+        Output a function call with the correct network device configuration.
+        The configuration must match the syntax of the specific network device and model.
+        It must be a function from the list of tools.
+        Functions must be named exactly as in the list of tools.
+        Don't output any commentary or markdown.
+        "#,
+        None
+    ));
+
+    chat.add_chat_message(ChatMessage::new(
+        ChatRole::User,
+        "configure unnumbered bgp on the cisco nexus 9000 switch using interface Ethernet1/1",
+        None
+    ));
+
+    chat.set_tool_choice(ToolChoice::Value(ToolChoiceValue::Auto));
+
+    chat.add_tool(Tool {
+        function: Function {
+            name: Some("configure_cisco_switch".to_string()),
+            description: Some("Sends a configuration to a Cisco Nexus 9000 switch".to_string()),
+            parameters: Some(ParametersBuilder::new()
+                .add_string_param("configuration", "The configuration to send to the switch", true)
+                .build()),
+        },
+        tool_type: ToolType::Function,
+    });
+
+    chat.add_tool(Tool {
+        function: Function {
+            name: Some("configure_juniper_switch".to_string()),
+            description: Some("Sends a configuration to a Juniper QFX switch".to_string()),
+            parameters: Some(ParametersBuilder::new()
+                .add_string_param("configuration", "The configuration to send to the switch", true)
+                .build()),
+        },
+        tool_type: ToolType::Function,
+    });
+
+    let response = chat.run_with_tools(|name, arguments| async move {
+        match name.as_str() {
+            "configure_cisco_switch" => configure_cisco_switch(arguments).await,
+            "configure_juniper_switch" => configure_juniper_switch(arguments).await,
+            _ => Ok(format!("unknown tool {}", name)),
+        }
+    }, DEFAULT_MAX_TOOL_ITERATIONS).await?;
+
+    println!("{:?}", response.choices[0].message.content);
+
+    Ok(())
+}
+
+async fn configure_cisco_switch(arguments: Value) -> Result<String, GroqError> {
+    println!("{}", arguments);
+    Ok("configuration applied to the cisco switch".to_string())
+}
+
+async fn configure_juniper_switch(arguments: Value) -> Result<String, GroqError> {
+    println!("{}", arguments);
+    Ok("configuration applied to the juniper switch".to_string())
+}
@@ -1,21 +1,30 @@
-use std::{env, error::Error, fmt::{self, Display}, pin::Pin, task::{Context, Poll}};
+use std::{collections::{BTreeMap, HashMap}, env, fmt::{self, Display}, fs, future::Future, hash::{Hash, Hasher}, ops::AddAssign, path::Path, pin::Pin, str::FromStr, task::{Context, Poll}, time::{Duration, Instant, SystemTime}};
 use futures::Stream;
 use futures::StreamExt;
-use futures::TryStreamExt;
 use serde_json::Value;
-use std::io::{self, ErrorKind};
+use std::io::{self, Write};
 use tokio_util::io::StreamReader;
-use tokio_util::codec::{FramedRead, LinesCodec};
-use tokio::io::BufReader;
-use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio_util::codec::{Decoder, FramedRead};
+use bytes::{Bytes, BytesMut};
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize};
 use thiserror::Error;
+use std::sync::{Arc, Mutex};
+use crate::error::GroqError;
+use crate::http::{HttpClient, HttpRequest, HttpResponse, MaybeSend};
+#[cfg(feature = "reqwest")]
+use crate::http::ReqwestConfig;
+use http::StatusCode;
+use http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 
+#[cfg(not(target_arch = "wasm32"))]
 pub struct BoxStreamUnpin<T>(Pin<Box<dyn Stream<Item = T> + Send>>);
+#[cfg(target_arch = "wasm32")]
+pub struct BoxStreamUnpin<T>(Pin<Box<dyn Stream<Item = T>>>);
 
 impl<T> BoxStreamUnpin<T> {
     pub fn new<S>(stream: S) -> Self
     where
-        S: Stream<Item = T> + Send + 'static,
+        S: Stream<Item = T> + MaybeSend + 'static,
     {
         BoxStreamUnpin(Box::pin(stream))
     }
@@ -30,27 +39,168 @@ impl<T> Stream for BoxStreamUnpin<T> {
 
 impl<T> Unpin for BoxStreamUnpin<T> {}
 
-pub trait BoxUnpinExt: Stream + Sized + Send + 'static {
+pub trait BoxUnpinExt: Stream + Sized + MaybeSend + 'static {
     fn boxed_unpin(self) -> BoxStreamUnpin<Self::Item> {
         BoxStreamUnpin::new(self)
     }
 }
 
-impl<T: Stream + Sized + Send + 'static> BoxUnpinExt for T {}
+impl<T: Stream + Sized + MaybeSend + 'static> BoxUnpinExt for T {}
+
+pub const DEFAULT_MAX_TOOL_ITERATIONS: usize = 10;
+
+pub const DEFAULT_BASE_URL: &str = "https://api.groq.com/openai/v1";
+
+/// Returns the API base URL from `GROQ_BASE_URL`, falling back to
+/// `DEFAULT_BASE_URL`.
+pub fn base_url_from_env() -> String {
+    env::var("GROQ_BASE_URL")
+        .map(|base_url| base_url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| DEFAULT_BASE_URL.to_string())
+}
+
+pub type RequestHook = Arc<dyn Fn(&Value) + Send + Sync>;
+pub type ResponseHook = Arc<dyn Fn(&ChatResponse) + Send + Sync>;
+
+pub type KeyProvider = Arc<dyn Fn() -> String + Send + Sync>;
+
+pub type ContentModerator = Arc<dyn Fn(&[ChatMessage]) -> Result<(), String> + Send + Sync>;
 
 #[derive(Clone)]
 pub struct Chat {
     api_key: String,
-    api_url: String,
+    key_provider: Option<KeyProvider>,
+    base_url: String,
+    api_url: Option<String>,
     chat_request: ChatRequest,
+    content_moderator: Option<ContentModerator>,
+    session_usage: Arc<Mutex<ChatUsage>>,
+    serialize_empty_tools: bool,
+    response_cache: Arc<Mutex<HashMap<String, ChatResponse>>>,
+    capability_table: CapabilityTable,
+    enforce_service_tier: bool,
+    validate_before_send: bool,
+    headers: HeaderMap,
+    max_stream_chunks: Option<usize>,
+    first_token_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_message_tokens: Option<usize>,
+    compact_whitespace: bool,
+    include_reasoning_in_history: bool,
+    assistant_prefill: Option<String>,
+    retry_policy: RetryPolicy,
+    custom_http_client: Option<Arc<dyn HttpClient>>,
+    request_hooks: Vec<RequestHook>,
+    response_hooks: Vec<ResponseHook>,
+    #[cfg(feature = "reqwest")]
+    reqwest_config: ReqwestConfig,
 }
 
 impl Chat {
     pub fn new(api_key: String, model: String) -> Self {
+        Self::from_request(api_key, ChatRequest::new(model, vec![]))
+    }
+
+    /// Wraps an already built or deserialized request.
+    pub fn from_request(api_key: String, chat_request: ChatRequest) -> Self {
         Self {
             api_key,
-            api_url: "https://api.groq.com/openai/v1/chat/completions".to_string(),
-            chat_request: ChatRequest::new(model, vec![]),
+            key_provider: None,
+            base_url: base_url_from_env(),
+            api_url: None,
+            chat_request,
+            content_moderator: None,
+            session_usage: Arc::new(Mutex::new(ChatUsage::default())),
+            serialize_empty_tools: false,
+            response_cache: Arc::new(Mutex::new(HashMap::new())),
+            capability_table: CapabilityTable::default(),
+            enforce_service_tier: false,
+            validate_before_send: false,
+            headers: HeaderMap::new(),
+            max_stream_chunks: None,
+            first_token_timeout: None,
+            idle_timeout: None,
+            max_message_tokens: None,
+            compact_whitespace: false,
+            include_reasoning_in_history: true,
+            assistant_prefill: None,
+            retry_policy: RetryPolicy::default(),
+            custom_http_client: None,
+            request_hooks: vec![],
+            response_hooks: vec![],
+            #[cfg(feature = "reqwest")]
+            reqwest_config: ReqwestConfig::default(),
+        }
+    }
+
+    /// Returns the request as it is built up, e.g. to save it for a later
+    /// `from_request`.
+    pub fn get_request(&self) -> &ChatRequest {
+        &self.chat_request
+    }
+
+    /// Returns a copy of this chat that authenticates with `api_key`. The copy
+    /// starts its own session usage and response cache so tenants are kept apart.
+    pub fn with_api_key(&self, api_key: String) -> Chat {
+        Chat {
+            api_key,
+            key_provider: None,
+            session_usage: Arc::new(Mutex::new(ChatUsage::default())),
+            response_cache: Arc::new(Mutex::new(HashMap::new())),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this chat that calls `key_provider` before every request
+    /// to fetch the current API key, so rotated keys are picked up without
+    /// rebuilding the chat.
+    pub fn with_key_provider(&self, key_provider: Box<dyn Fn() -> String + Send + Sync>) -> Chat {
+        Chat {
+            key_provider: Some(Arc::from(key_provider)),
+            ..self.clone()
+        }
+    }
+
+    /// Captures the request, endpoint, session usage and the settings that shape
+    /// the request body so the conversation can be persisted. The API key, key
+    /// provider, headers and transport settings are not included.
+    pub fn export_session(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            request: self.chat_request.clone(),
+            base_url: self.base_url.clone(),
+            api_url: self.api_url.clone(),
+            session_usage: self.session_usage(),
+            assistant_prefill: self.assistant_prefill.clone(),
+            include_reasoning_in_history: self.include_reasoning_in_history,
+            compact_whitespace: self.compact_whitespace,
+            serialize_empty_tools: self.serialize_empty_tools,
+            max_message_tokens: self.max_message_tokens,
+        }
+    }
+
+    /// Restores a snapshot taken with `export_session`, keeping this chat's
+    /// credentials and transport settings.
+    pub fn import_session(&mut self, snapshot: SessionSnapshot) {
+        self.chat_request = snapshot.request;
+        self.base_url = snapshot.base_url;
+        self.api_url = snapshot.api_url;
+        *self.session_usage.lock().unwrap() = snapshot.session_usage;
+        self.assistant_prefill = snapshot.assistant_prefill;
+        self.include_reasoning_in_history = snapshot.include_reasoning_in_history;
+        self.compact_whitespace = snapshot.compact_whitespace;
+        self.serialize_empty_tools = snapshot.serialize_empty_tools;
+        self.max_message_tokens = snapshot.max_message_tokens;
+    }
+
+    /// Returns a copy of this chat that sends its requests through
+    /// `http_client` instead of the default `reqwest` transport.
+    ///
+    /// The proxy, certificate, connect timeout and overall timeout settings only
+    /// configure the default transport and are ignored by a custom one.
+    pub fn with_http_client<C: HttpClient + 'static>(&self, http_client: C) -> Chat {
+        Chat {
+            custom_http_client: Some(Arc::new(http_client)),
+            ..self.clone()
         }
     }
 
@@ -62,8 +212,65 @@ impl Chat {
         self.chat_request.messages.clone()
     }
 
+    /// Overrides the full chat completions URL, ignoring the base URL.
     pub fn set_api_url(&mut self, api_url: String) {
-        self.api_url = api_url;
+        self.api_url = Some(api_url);
+    }
+
+    /// Sets the host and version prefix that endpoint paths are appended to.
+    pub fn set_base_url(&mut self, base_url: String) {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+    }
+
+    pub fn get_base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Returns the URL chat completions are posted to.
+    pub fn chat_url(&self) -> String {
+        self.api_url.clone().unwrap_or_else(|| format!("{}/chat/completions", self.base_url))
+    }
+
+    /// Routes requests through the proxy at `url`, taking precedence over the
+    /// `HTTPS_PROXY` environment variable.
+    #[cfg(feature = "reqwest")]
+    pub fn set_proxy(&mut self, url: String) -> Result<(), String> {
+        reqwest::Proxy::all(&url).map_err(|e| format!("Invalid proxy url {}: {}", url, e))?;
+        self.reqwest_config.proxy = Some(url);
+        Ok(())
+    }
+
+    /// Sets basic auth credentials for the proxy, whether configured with
+    /// `set_proxy` or taken from `HTTPS_PROXY`.
+    #[cfg(feature = "reqwest")]
+    pub fn set_proxy_auth(&mut self, username: String, password: String) {
+        self.reqwest_config.proxy_auth = Some((username, password));
+    }
+
+    /// Trusts `certificate` in addition to the system roots, e.g. the CA of a
+    /// proxy that terminates TLS.
+    #[cfg(feature = "reqwest")]
+    pub fn add_root_certificate(&mut self, certificate: reqwest::Certificate) {
+        self.reqwest_config.root_certificates.push(certificate);
+    }
+
+    /// Disables TLS certificate validation entirely.
+    ///
+    /// This is insecure: any certificate, including expired or self-signed ones
+    /// for the wrong host, is accepted, exposing the API key and conversation to
+    /// anyone able to intercept the connection. Prefer `add_root_certificate`.
+    #[cfg(feature = "reqwest")]
+    pub fn set_danger_accept_invalid_certs(&mut self, danger_accept_invalid_certs: bool) {
+        self.reqwest_config.danger_accept_invalid_certs = danger_accept_invalid_certs;
+    }
+
+    /// Adds a header sent with every request after the standard ones, replacing
+    /// any earlier value for the same name.
+    pub fn set_header(&mut self, name: String, value: String) -> Result<(), String> {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+        let value = HeaderValue::from_str(&value).map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+        self.headers.insert(name, value);
+        Ok(())
     }
 
     pub fn set_model(&mut self, model: String) {
@@ -74,10 +281,70 @@ impl Chat {
         self.chat_request.messages.push(message);
     }
 
+    /// Uses the contents of the file at `path` as the system prompt, replacing a
+    /// leading system message or inserting one at the front.
+    pub fn system_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), GroqError> {
+        let path = path.as_ref();
+        let prompt = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to read system prompt {}: {}", path.display(), e))
+        })?;
+        match self.chat_request.messages.first_mut() {
+            Some(message) if message.role == ChatRole::System => message.content = Some(prompt),
+            _ => self.chat_request.messages.insert(0, ChatMessage::new(ChatRole::System, &prompt, None)),
+        }
+        Ok(())
+    }
+
+    /// Appends the first choice's message of `response` to the conversation.
+    pub fn push_response(&mut self, response: &ChatResponse) {
+        if let Some(choice) = response.choices.first() {
+            self.chat_request.messages.push(choice.to_message());
+        }
+    }
+
+    /// Appends the assistant message carrying tool calls from `response`, which
+    /// must precede the tool results in the next request. Does nothing when the
+    /// response has no tool calls.
+    pub fn append_assistant_tool_calls(&mut self, response: &ChatResponse) {
+        let Some(choice) = response.choices.first() else {
+            return;
+        };
+        if choice.message.tool_calls.as_ref().is_some_and(|tool_calls| !tool_calls.is_empty()) {
+            self.chat_request.messages.push(choice.to_message());
+        }
+    }
+
+    /// Appends tool result messages given as `(tool_call_id, content)` pairs in
+    /// the order of `tool_calls`, whatever order the results arrived in. Results
+    /// whose id matches no tool call are appended last.
+    pub fn add_tool_results(&mut self, tool_calls: &[ToolCall], mut results: Vec<(String, String)>) {
+        results.sort_by_key(|(tool_call_id, _)| {
+            tool_calls.iter().position(|tool_call| &tool_call.id == tool_call_id).unwrap_or(usize::MAX)
+        });
+        for (tool_call_id, content) in results {
+            self.chat_request.messages.push(ChatMessage::new(ChatRole::Tool, &content, Some(tool_call_id)));
+        }
+    }
+
     pub fn clear_chat_messages(&mut self) {
         self.chat_request.messages.clear();
     }
 
+    /// Inserts `message` before the message at `index`. An index past the end
+    /// appends the message.
+    pub fn insert_chat_message(&mut self, index: usize, message: ChatMessage) {
+        let index = index.min(self.chat_request.messages.len());
+        self.chat_request.messages.insert(index, message);
+    }
+
+    pub fn replace_chat_message(&mut self, index: usize, message: ChatMessage) -> Result<(), String> {
+        let number_of_messages = self.chat_request.messages.len();
+        let slot = self.chat_request.messages.get_mut(index)
+            .ok_or_else(|| format!("Message index {} out of range for {} messages", index, number_of_messages))?;
+        *slot = message;
+        Ok(())
+    }
+
     pub fn remove_last_n_chat_messages(&mut self, n: usize) {
         self.chat_request.messages.truncate(self.chat_request.messages.len() - n);
     }
@@ -102,7 +369,10 @@ impl Chat {
     }
 
     pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) -> Result<(), String> {
-        if frequency_penalty < -2.0 || frequency_penalty > 2.0 {
+        if !frequency_penalty.is_finite() {
+            return Err(format!("Frequency penalty must be a finite number, got {}", frequency_penalty));
+        }
+        if !(-2.0..=2.0).contains(&frequency_penalty) {
             return Err("Frequency penalty must be between -2.0 and 2.0".to_string());
         }
         self.chat_request.frequency_penalty = frequency_penalty;
@@ -118,15 +388,18 @@ impl Chat {
     }
 
     pub fn set_presence_penalty(&mut self, presence_penalty: f32) -> Result<(), String> {
-        if presence_penalty < -2.0 || presence_penalty > 2.0 {
+        if !presence_penalty.is_finite() {
+            return Err(format!("Presence penalty must be a finite number, got {}", presence_penalty));
+        }
+        if !(-2.0..=2.0).contains(&presence_penalty) {
             return Err("Presence penalty must be between -2.0 and 2.0".to_string());
         }
         self.chat_request.presence_penalty = presence_penalty;
         Ok(())
     }
 
-    pub fn set_reasoning_format(&mut self, reasoning_format: &str){
-        self.chat_request.reasoning_format = Some(reasoning_format.to_string());
+    pub fn set_reasoning_format(&mut self, reasoning_format: ReasoningFormat){
+        self.chat_request.reasoning_format = Some(reasoning_format);
     }
 
     pub fn set_response_format(&mut self, response_format: ChatResponseFormat){
@@ -141,8 +414,26 @@ impl Chat {
         self.chat_request.service_tier = Some(service_tier);
     }
 
+    pub fn clear_seed(&mut self) {
+        self.chat_request.seed = None;
+    }
+
+    pub fn clear_response_format(&mut self) {
+        self.chat_request.response_format = None;
+    }
+
+    pub fn clear_service_tier(&mut self) {
+        self.chat_request.service_tier = None;
+    }
+
+    /// Resets the frequency and presence penalties to their default of 0.0.
+    pub fn reset_penalties(&mut self) {
+        self.chat_request.frequency_penalty = 0.0;
+        self.chat_request.presence_penalty = 0.0;
+    }
+
     pub fn set_temperature(&mut self, temperature: f32) -> Result<(), String> {
-        if temperature < 0.0 || temperature > 2.0 {
+        if !(0.0..=2.0).contains(&temperature) {
             return Err("Temperature must be between 0.0 and 1.0".to_string());
         }
         self.chat_request.temperature = temperature;
@@ -150,13 +441,35 @@ impl Chat {
     }
 
     pub fn set_top_p(&mut self, top_p: f32) -> Result<(), String> {
-        if top_p < 0.0 || top_p > 1.0 {
+        if !top_p.is_finite() {
+            return Err(format!("Top P must be a finite number, got {}", top_p));
+        }
+        if !(0.0..=1.0).contains(&top_p) {
             return Err("Top P must be between 0.0 and 1.0".to_string());
         }
         self.chat_request.top_p = top_p;
         Ok(())
     }
 
+    /// Sends `key` with `value` at the top level of the request body, for
+    /// parameters this crate has no typed setter for yet. Keys of typed
+    /// parameters are rejected; use their setters instead.
+    pub fn set_extra_param(&mut self, key: String, value: Value) -> Result<(), String> {
+        if ChatRequest::TYPED_PARAMS.contains(&key.as_str()) {
+            return Err(format!("{} is a typed parameter and cannot be set as an extra parameter", key));
+        }
+        self.chat_request.extra_params.insert(key, value);
+        Ok(())
+    }
+
+    pub fn remove_extra_param(&mut self, key: &str) {
+        self.chat_request.extra_params.remove(key);
+    }
+
+    pub fn set_user(&mut self, user: String) {
+        self.chat_request.user = Some(user);
+    }
+
     pub fn set_tool_choice(&mut self, tool_choice: ToolChoice) {
         self.chat_request.tool_choice = Some(tool_choice);
     }
@@ -169,6 +482,80 @@ impl Chat {
         self.chat_request.tools.clear();
     }
 
+    pub fn set_tools(&mut self, tools: Vec<Tool>) {
+        self.chat_request.tools = tools;
+    }
+
+    pub fn get_tools(&self) -> Vec<Tool> {
+        self.chat_request.tools.clone()
+    }
+
+    /// Sends `"tools": []` instead of omitting the field when no tools are set,
+    /// for gateways that distinguish the two.
+    pub fn set_serialize_empty_tools(&mut self, serialize_empty_tools: bool) {
+        self.serialize_empty_tools = serialize_empty_tools;
+    }
+
+    /// Ends a stream with an error once more than `max_stream_chunks` chunks
+    /// arrive, guarding against servers that never finish.
+    pub fn set_max_stream_chunks(&mut self, max_stream_chunks: usize) {
+        self.max_stream_chunks = Some(max_stream_chunks);
+    }
+
+    /// Fails a stream with `GroqError::FirstTokenTimeout` when no content has
+    /// arrived within `first_token_timeout` of sending the request. Once content
+    /// flows, the rest of the generation is not bounded by this timeout.
+    pub fn set_first_token_timeout(&mut self, first_token_timeout: Duration) {
+        self.first_token_timeout = Some(first_token_timeout);
+    }
+
+    /// Controls whether the `reasoning` of earlier assistant messages is sent
+    /// back to the model. Turning it off saves tokens. On by default.
+    pub fn set_include_reasoning_in_history(&mut self, include_reasoning_in_history: bool) {
+        self.include_reasoning_in_history = include_reasoning_in_history;
+    }
+
+    /// Seeds the reply with `text`: it is sent as a trailing assistant message,
+    /// after every other message, which the model continues instead of starting
+    /// a new reply. The returned content holds only the continuation. The
+    /// prefill is sent with every request until it is cleared.
+    pub fn set_assistant_prefill(&mut self, text: &str) {
+        self.assistant_prefill = Some(text.to_string());
+    }
+
+    pub fn clear_assistant_prefill(&mut self) {
+        self.assistant_prefill = None;
+    }
+
+    /// Limits how long establishing the connection may take.
+    #[cfg(feature = "reqwest")]
+    pub fn set_connect_timeout(&mut self, connect_timeout: Duration) {
+        self.reqwest_config.connect_timeout = Some(connect_timeout);
+    }
+
+    /// Fails a stream with `GroqError::Timeout` when no bytes arrive for
+    /// `idle_timeout`, however long the stream has been running.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = Some(idle_timeout);
+    }
+
+    /// Limits the whole request, from connecting until the last byte of the
+    /// response or stream has been read.
+    #[cfg(feature = "reqwest")]
+    pub fn set_overall_timeout(&mut self, overall_timeout: Duration) {
+        self.reqwest_config.overall_timeout = Some(overall_timeout);
+    }
+
+    /// Collapses whitespace runs in outgoing message content to save tokens.
+    /// Fenced code blocks are sent unchanged.
+    pub fn set_compact_whitespace(&mut self, compact_whitespace: bool) {
+        self.compact_whitespace = compact_whitespace;
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
     pub fn get_temperature(&self) -> f32 {
         self.chat_request.temperature
     }
@@ -189,350 +576,4770 @@ impl Chat {
         self.chat_request.top_p
     }
 
-    pub async fn send(&self) -> Result<ChatResponse, Box<dyn Error + Send + Sync>> {
-        let mut client_builder = reqwest::Client::builder();
-        if let Ok(proxy) = env::var("HTTPS_PROXY"){
-            client_builder = client_builder.proxy(reqwest::Proxy::all(proxy)?);
-        }
-        let client = client_builder.build()?;
-        let body = serde_json::to_string(&self.chat_request)?;
-        let response = match client
-            .post(self.api_url.clone())
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .body(body)
-            .send()
-            .await{
-                Ok(response) => response,
-                Err(e) => {
-                    return Err(Box::new(e));
-                }
-            };
-        if response.status().is_client_error() {
-            let raw_response = response.text().await?;
-            eprintln!("{}", raw_response);
-            let chat_error = serde_json::from_str::<ChatError>(&raw_response)?;
-            return Err(Box::new(chat_error));
+    pub fn get_parallel_tool_calls(&self) -> bool {
+        self.chat_request.parallel_tool_calls
+    }
+
+    pub fn get_seed(&self) -> Option<u64> {
+        self.chat_request.seed
+    }
+
+    pub fn get_max_completion_tokens(&self) -> Option<u32> {
+        self.chat_request.max_completion_tokens
+    }
+
+    pub fn get_service_tier(&self) -> Option<ChatServiceTier> {
+        self.chat_request.service_tier.clone()
+    }
+
+    pub fn get_response_format(&self) -> Option<ChatResponseFormat> {
+        self.chat_request.response_format.clone()
+    }
+
+    /// Registers a check that runs on the conversation before every `send` and
+    /// `stream`. Returning an error blocks the request.
+    pub fn set_content_moderator<F>(&mut self, content_moderator: F)
+    where
+        F: Fn(&[ChatMessage]) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.content_moderator = Some(Arc::new(content_moderator));
+    }
+
+    /// Registers a callback invoked with every request body right before it is
+    /// sent, exactly as it goes over the wire. The hook gets the JSON rather
+    /// than the `ChatRequest` because the body also carries what the request
+    /// struct does not: the `stream` flag, the assistant prefill, compacted
+    /// whitespace and the dropped history reasoning.
+    pub fn on_request(&mut self, hook: Box<dyn Fn(&Value) + Send + Sync>) {
+        self.request_hooks.push(Arc::from(hook));
+    }
+
+    /// Registers a callback invoked with every response, or with every chunk
+    /// when streaming.
+    pub fn on_response(&mut self, hook: Box<dyn Fn(&ChatResponse) + Send + Sync>) {
+        self.response_hooks.push(Arc::from(hook));
+    }
+
+    /// Runs the checks every request has to pass before it is transmitted.
+    fn preflight(&self) -> Result<(), GroqError> {
+        self.moderate()?;
+        let problems = match self.validate_before_send {
+            true => self.validate().err().unwrap_or_default(),
+            false => self.send_problems(),
+        };
+        if !problems.is_empty() {
+            return Err(GroqError::Validation(problems.join("; ")));
         }
-        let raw_response = response.text().await?;
-        let chat_response = serde_json::from_str::<ChatResponse>(&raw_response)?;
-        Ok(chat_response)
+        #[cfg(feature = "tracing")]
+        if let Err(e) = self.check_service_tier() {
+            tracing::warn!("{}", e);
+        }
+        Ok(())
     }
 
-    pub async fn stream(&self) -> Result<impl Stream<Item = Result<ChatResponse, Box<dyn std::error::Error + Send + Sync>>> + Unpin, Box<dyn std::error::Error + Send + Sync>> {
-        let client = reqwest::Client::new();
-        let body = serde_json::to_string(&self.chat_request)?;
-        let response = client
-            .post(self.api_url.clone())
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .body(body)
-            .send()
-            .await?;
-    
-        if response.status().is_client_error() {
-            let raw_response = response.text().await?;
-            let chat_error = serde_json::from_str::<ChatError>(&raw_response)?;
-            return Err(Box::new(chat_error));
+    /// Runs `validate` before every `send` and `stream`, failing the request on
+    /// any problem. Off by default, when only the checks that always apply run:
+    /// the `tool_choice` check, the per-message limit and an enforced service
+    /// tier.
+    pub fn set_validate_before_send(&mut self, validate_before_send: bool) {
+        self.validate_before_send = validate_before_send;
+    }
+
+    /// Checks the request as a whole and returns every problem found, such as a
+    /// `tool_choice` naming a function that is not among the tools, an empty
+    /// conversation, or JSON mode without any message asking for JSON. Service
+    /// tier mismatches only count when `set_enforce_service_tier` is on.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+        if self.chat_request.messages.is_empty() {
+            problems.push("The request has no messages".to_string());
         }
-    
-        let byte_stream = response
-            .bytes_stream()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e));
-        let stream_reader = StreamReader::new(byte_stream);
-        let buf_reader = BufReader::new(stream_reader);
-        let lines = FramedRead::new(buf_reader, LinesCodec::new());
-    
-        let json_stream = lines.filter_map(|line_result| async {
-            match line_result {
-                Ok(line) => {
-                    let trimmed = line.trim();
-                    if trimmed.is_empty() {
-                        return None;
-                    }
-                    let json_str = if let Some(stripped) = trimmed.strip_prefix("data:") {
-                        stripped.trim()
-                    } else {
-                        trimmed
-                    };
-                    if json_str == "[DONE]" {
-                        return None;
-                    }
-    
-                    Some(serde_json::from_str::<ChatResponse>(json_str)
-                        .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>))
-                }
-                Err(e) => Some(Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>)),
+        problems.extend(self.send_problems());
+        if let Some(ChatResponseFormat::JsonObject | ChatResponseFormat::JsonArray) = &self.chat_request.response_format {
+            let mentions_json = self.chat_request.messages.iter()
+                .filter_map(|message| message.content.as_deref())
+                .chain(self.assistant_prefill.as_deref())
+                .any(|content| content.to_lowercase().contains("json"));
+            if !mentions_json {
+                problems.push("JSON mode requires a message instructing the model to reply in JSON".to_string());
             }
-        });
-        Ok(json_stream.boxed_unpin())
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
     }
-}
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatMessage {
-    pub role: ChatRole,
-    pub content: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tool_calls: Option<Vec<ToolCall>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tool_call_id: Option<String>,
-}
+    /// The problems that fail a request even without `set_validate_before_send`.
+    fn send_problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Err(e) = self.validate_tool_choice() {
+            problems.push(e);
+        }
+        if let Some(index) = self.oversized_message_index() {
+            problems.push(format!(
+                "Message {} exceeds the per-message limit of {} estimated tokens",
+                index,
+                self.max_message_tokens.unwrap_or_default()
+            ));
+        }
+        if self.enforce_service_tier {
+            if let Err(e) = self.check_service_tier() {
+                problems.push(e);
+            }
+        }
+        problems
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ToolCall {
-    pub id: String,
-    pub r#type: ToolType,
-    pub function: ToolCallFunction,
-}
+    fn moderate(&self) -> Result<(), GroqError> {
+        match &self.content_moderator {
+            Some(moderator) => moderator(&self.chat_request.messages).map_err(GroqError::Moderation),
+            None => Ok(()),
+        }
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ToolCallFunction {
-    pub name: String,
-    pub arguments: String,
-}
+    /// Returns the usage summed over every `send` since the session started or
+    /// was last reset. Clones of a `Chat` share the same session.
+    pub fn session_usage(&self) -> ChatUsage {
+        self.session_usage.lock().unwrap().clone()
+    }
 
-impl ChatMessage {
-    pub fn new(role: ChatRole, content: &str, tool_call_id: Option<String>) -> Self {
-        Self {
-            role,
-            content: Some(content.to_string()),
-            tool_calls: None,
-            tool_call_id,
+    pub fn reset_session_usage(&mut self) {
+        *self.session_usage.lock().unwrap() = ChatUsage::default();
+    }
+
+    /// Checks that a `tool_choice` forcing a specific function names one of the
+    /// registered tools, and that `required` comes with at least one tool.
+    fn validate_tool_choice(&self) -> Result<(), String> {
+        match &self.chat_request.tool_choice {
+            Some(ToolChoice::Object(tool_choice)) => {
+                let Some(name) = &tool_choice.function.name else {
+                    return Ok(());
+                };
+                let registered = self.chat_request.tools.iter()
+                    .any(|tool| tool.function.name.as_ref() == Some(name));
+                if !registered {
+                    return Err(format!("tool_choice forces function '{}' which is not in the tools list", name));
+                }
+            }
+            Some(ToolChoice::Value(ToolChoiceValue::Required)) if self.chat_request.tools.is_empty() => {
+                return Err("tool_choice is required but no tools are given".to_string());
+            }
+            _ => {}
         }
+        Ok(())
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-pub enum ChatResponseFormat {
-    JsonObject,
-    JsonArray,
-    Text,
-}
+    pub fn set_capability_table(&mut self, capability_table: CapabilityTable) {
+        self.capability_table = capability_table;
+    }
 
-impl ChatResponseFormat {
-    pub fn to_string(&self) -> String {
-        match self {
-            ChatResponseFormat::JsonObject => r#"{ "type": "json_object" }"#.to_string(),
-            ChatResponseFormat::JsonArray => r#"{ "type": "json_array" }"#.to_string(),
-            ChatResponseFormat::Text => r#"{ "type": "text" }"#.to_string(),
+    /// Makes an incompatible model/service tier pair fail the request. Otherwise
+    /// the mismatch is only logged as a warning with the `tracing` feature, and
+    /// can be checked up front with `check_service_tier`.
+    pub fn set_enforce_service_tier(&mut self, enforce_service_tier: bool) {
+        self.enforce_service_tier = enforce_service_tier;
+    }
+
+    /// Checks the configured service tier against the capability table. Models
+    /// missing from the table are assumed to support every tier.
+    ///
+    /// Unless the tier is enforced, a mismatch does not stop `send`; call this
+    /// first to surface the advisory without the `tracing` feature:
+    ///
+    /// ```
+    /// use groq_client_rs::chat::{CapabilityTable, Chat, ChatServiceTier, ModelCapabilities};
+    ///
+    /// let mut capability_table = CapabilityTable::new();
+    /// capability_table.insert("small-model", ModelCapabilities {
+    ///     service_tiers: Some(vec![ChatServiceTier::OnDemand]),
+    ///     ..Default::default()
+    /// });
+    /// let mut chat = Chat::new("api-key".to_string(), "small-model".to_string());
+    /// chat.set_capability_table(capability_table);
+    /// chat.set_service_tier(ChatServiceTier::Flex);
+    /// if let Err(advisory) = chat.check_service_tier() {
+    ///     eprintln!("Warning: {}", advisory);
+    /// }
+    /// ```
+    pub fn check_service_tier(&self) -> Result<(), String> {
+        let Some(service_tier) = &self.chat_request.service_tier else {
+            return Ok(());
+        };
+        let supported = self.capability_table.get(&self.chat_request.model)
+            .and_then(|capabilities| capabilities.service_tiers.as_ref());
+        match supported {
+            Some(service_tiers) if !service_tiers.contains(service_tier) => Err(format!(
+                "Service tier {} is not supported by model {}",
+                service_tier, self.chat_request.model
+            )),
+            _ => Ok(()),
         }
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-pub enum ChatServiceTier {
-    OnDemand,
-    Auto,
-    Flex,
-}
+    /// Share of the model's context window used by `response`, with the window
+    /// taken from the capability table. `None` if the window is unknown.
+    pub fn context_utilization(&self, response: &ChatResponse) -> Option<f64> {
+        let context_window = self.capability_table.get(&response.model)?.context_window?;
+        Some(response.context_utilization(context_window))
+    }
 
-impl ChatServiceTier {
-    pub fn to_string(&self) -> String {
-        match self {
-            ChatServiceTier::OnDemand => "on_demand".to_string(),
-            ChatServiceTier::Auto => "auto".to_string(),
-            ChatServiceTier::Flex => "flex".to_string(),
+    /// Clears the service tier if the model does not support it, letting the API
+    /// pick its default. Returns true when the tier was changed.
+    pub fn coerce_service_tier(&mut self) -> bool {
+        if self.check_service_tier().is_ok() {
+            return false;
         }
+        self.chat_request.service_tier = None;
+        true
     }
-}
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct ChatRequest {
-    model: String,
-    messages: Vec<ChatMessage>,
-    frequency_penalty: f32,
-    max_completion_tokens: Option<u32>,
-    parallel_tool_calls: bool,
-    presence_penalty: f32,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    reasoning_format: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    response_format: Option<ChatResponseFormat>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    seed: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    service_tier: Option<ChatServiceTier>,
-    stream: bool,
-    temperature: f32,
-    top_p: f32,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    tool_choice: Option<ToolChoice>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    tools: Vec<Tool>,
-}
+    /// Rough prompt size of the conversation, using `estimate_tokens` on each
+    /// message's content and tool calls.
+    pub fn estimated_prompt_tokens(&self) -> usize {
+        self.chat_request.messages.iter().map(ChatMessage::estimated_tokens).sum()
+    }
 
-impl ChatRequest {
-    pub fn new(model: String, messages: Vec<ChatMessage>) -> Self {
-        Self { 
-            model,
-            messages,
-            frequency_penalty: 0.0,
-            max_completion_tokens: None,
-            parallel_tool_calls: true,
-            presence_penalty: 0.0,
-            reasoning_format: None,
-            response_format: None,
-            seed: None,
-            service_tier: None,
-            stream: false,
-            temperature: 1.0,
-            top_p: 1.0,
-            tool_choice: None,
-            tools: vec![],
-        }
+    /// Rejects requests containing a message whose estimated size exceeds
+    /// `max_message_tokens`.
+    pub fn set_max_message_tokens(&mut self, max_message_tokens: usize) {
+        self.max_message_tokens = Some(max_message_tokens);
     }
-}
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-pub enum ToolChoiceValue {
-    None,
-    Auto,
-    Required,
-}
+    /// Returns the index of the first message over the per-message token limit,
+    /// if a limit is set.
+    pub fn oversized_message_index(&self) -> Option<usize> {
+        let max_message_tokens = self.max_message_tokens?;
+        self.chat_request.messages.iter()
+            .position(|message| message.estimated_tokens() > max_message_tokens)
+    }
 
-/// Represents the object variant for tool choice.
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ToolChoiceObject {
-    /// The type of the tool. Currently, only "function" is supported.
-    #[serde(rename = "type")]
-    pub tool_type: ToolType,
-    /// The tool details.
-    pub function: ToolFunction,
-}
+    /// Drops the oldest non-system messages until the estimated prompt size fits
+    /// `max_prompt_tokens`. System messages are always kept, and tool results
+    /// whose tool call was dropped are removed with it.
+    pub fn trim_to_token_budget(&mut self, max_prompt_tokens: usize) {
+        let messages = &mut self.chat_request.messages;
+        let mut total: usize = messages.iter().map(ChatMessage::estimated_tokens).sum();
+        while total > max_prompt_tokens {
+            let Some(index) = messages.iter().position(|message| message.role != ChatRole::System) else {
+                break;
+            };
+            total -= messages.remove(index).estimated_tokens();
+            while messages.get(index).is_some_and(|message| message.role == ChatRole::Tool) {
+                total -= messages.remove(index).estimated_tokens();
+            }
+        }
+    }
 
-/// Represents the tool details.
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ToolFunction {
-    /// The name of the function to call.
-    pub name: Option<String>,
-}
+    /// A small, content-free description of the conversation for structured logs.
+    pub fn log_summary(&self) -> ConversationSummary {
+        let mut role_counts = BTreeMap::new();
+        for message in &self.chat_request.messages {
+            *role_counts.entry(message.role.clone()).or_insert(0) += 1;
+        }
+        ConversationSummary {
+            message_count: self.chat_request.messages.len(),
+            role_counts,
+            model: self.chat_request.model.clone(),
+            total_tool_calls: self.chat_request.messages.iter()
+                .map(|message| message.tool_calls.as_ref().map_or(0, Vec::len))
+                .sum(),
+            estimated_tokens: self.estimated_prompt_tokens(),
+        }
+    }
 
-/// An untagged enum that can either be a string (for simple values) or an object (for a specific tool).
-#[derive(Clone, Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-pub enum ToolChoice {
-    Value(ToolChoiceValue),
-    Object(ToolChoiceObject),
-}
+    pub fn request_size_bytes(&self) -> Result<usize, serde_json::Error> {
+        Ok(self.request_json()?.len())
+    }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Tool {
-    /// A description of what the function does.
-    pub function: Function,
-    /// The type of the tool. Currently, only "function" is supported.
-    #[serde(rename = "type")]
-    pub tool_type: ToolType,
-}
+    /// The exact JSON body the next request would send, without sending it.
+    pub fn request_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.request_value()?)
+    }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Function {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub description: Option<String>,
-    /// The name of the function to be called. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub name: Option<String>,
-    /// The parameters the function accepts, described as a JSON Schema object.
-    /// Omitting parameters defines a function with an empty parameter list.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub parameters: Option<Value>,
-}
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum ToolType {
-    #[serde(rename = "function")]
-    Function
-}
+    /// The body the next request would send, as a JSON value.
+    pub fn request_value(&self) -> Result<Value, serde_json::Error> {
+        let mut request = serde_json::to_value(&self.chat_request)?;
+        if self.chat_request.tools.is_empty() {
+            // Some backends reject parallel_tool_calls when no tools are given.
+            if let Some(request) = request.as_object_mut() {
+                request.remove("parallel_tool_calls");
+            }
+            if self.serialize_empty_tools {
+                request["tools"] = Value::Array(vec![]);
+            }
+        }
+        if !self.include_reasoning_in_history {
+            if let Some(messages) = request.get_mut("messages").and_then(Value::as_array_mut) {
+                for message in messages.iter_mut().filter_map(Value::as_object_mut) {
+                    if message.get("role").and_then(Value::as_str) == Some("assistant") {
+                        message.remove("reasoning");
+                    }
+                }
+            }
+        }
+        if let Some(prefill) = &self.assistant_prefill {
+            if let Some(messages) = request.get_mut("messages").and_then(Value::as_array_mut) {
+                messages.push(serde_json::to_value(ChatMessage::new(ChatRole::Assistant, prefill, None))?);
+            }
+        }
+        if self.compact_whitespace {
+            if let Some(messages) = request.get_mut("messages").and_then(Value::as_array_mut) {
+                for message in messages {
+                    if let Some(Value::String(content)) = message.get_mut("content") {
+                        *content = compact_whitespace(content);
+                    }
+                }
+            }
+        }
+        Ok(request)
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "lowercase")] 
-pub enum ChatRole {
-    User,
-    Assistant,
-    System,
-    Tool,
-}
+    fn api_key(&self) -> String {
+        match &self.key_provider {
+            Some(key_provider) => key_provider(),
+            None => self.api_key.clone(),
+        }
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatResponse {
-    pub id: String,
-    pub object: String,
-    pub created: u64,
-    pub model: String,
-    pub choices: Vec<ChatChoice>,
-    pub usage: Option<ChatUsage>,
-    pub system_fingerprint: String,
-    pub x_groq: ChatXGroq,
-}
+    /// The custom transport if one is set, otherwise the default one.
+    fn transport(&self) -> Result<Arc<dyn HttpClient>, GroqError> {
+        if let Some(http_client) = &self.custom_http_client {
+            return Ok(http_client.clone());
+        }
+        #[cfg(feature = "reqwest")]
+        return Ok(Arc::new(self.reqwest_config.build()?));
+        #[cfg(not(feature = "reqwest"))]
+        Err(GroqError::Validation(
+            "No HTTP transport: enable the reqwest feature or use Chat::with_http_client".to_string(),
+        ))
+    }
 
-#[derive(Clone, Serialize, Debug)]
-pub struct ChatChoice {
-    pub index: u64,
-    pub message: ChatMessage,
-    pub logprobs: Option<String>,
-    pub finish_reason: Option<String>,
-}
-impl<'de> Deserialize<'de> for ChatChoice {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    async fn post(&self, request: &Value) -> Result<HttpResponse, GroqError> {
+        self.request_hooks.iter().for_each(|hook| hook(request));
+        let body = serde_json::to_string(request)?;
+        let mut headers = self.request_headers()?;
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let request = HttpRequest {
+            url: self.chat_url(),
+            headers,
+            body,
+        };
+        self.transport()?.post(request).await
+    }
+
+    /// Sends a GET request to the endpoint made of `path_segments` below the
+    /// base URL. Each segment is percent-encoded.
+    pub(crate) async fn get(&self, path_segments: &[&str]) -> Result<HttpResponse, GroqError> {
+        let mut url = url::Url::parse(&self.base_url)
+            .map_err(|e| GroqError::Validation(format!("Invalid base url {}: {}", self.base_url, e)))?;
+        url.path_segments_mut()
+            .map_err(|_| GroqError::Validation(format!("Invalid base url {}", self.base_url)))?
+            .pop_if_empty()
+            .extend(path_segments);
+        let request = HttpRequest {
+            url: url.to_string(),
+            headers: self.request_headers()?,
+            body: String::new(),
+        };
+        self.transport()?.get(request).await
+    }
+
+    /// The authorization header followed by the custom headers.
+    fn request_headers(&self) -> Result<HeaderMap, GroqError> {
+        let mut headers = HeaderMap::new();
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", self.api_key()))
+            .map_err(|e| GroqError::Validation(format!("Invalid API key: {}", e)))?;
+        headers.insert(AUTHORIZATION, authorization);
+        headers.extend(self.headers.clone());
+        Ok(headers)
+    }
+
+    pub async fn send(&self) -> Result<ChatResponse, GroqError> {
+        let (chat_response, _) = self.send_with_headers().await?;
+        Ok(chat_response)
+    }
+
+    /// Like `send`, but also returns the rate limit state reported by the API
+    /// so callers can throttle before hitting 429s.
+    pub async fn send_with_rate_limit(&self) -> Result<(ChatResponse, RateLimitInfo), GroqError> {
+        let (chat_response, headers) = self.send_with_headers().await?;
+        Ok((chat_response, RateLimitInfo::from_headers(&headers)))
+    }
+
+    async fn send_with_headers(&self) -> Result<(ChatResponse, HeaderMap), GroqError> {
+        #[cfg(feature = "tracing")]
+        {
+            use tracing::Instrument;
+            let span = tracing::info_span!(
+                "groq.send",
+                model = %self.chat_request.model,
+                prompt_tokens = tracing::field::Empty,
+                completion_tokens = tracing::field::Empty,
+                total_time = tracing::field::Empty,
+            );
+            self.send_with_headers_untraced().instrument(span).await
+        }
+        #[cfg(not(feature = "tracing"))]
+        self.send_with_headers_untraced().await
+    }
+
+    async fn send_with_headers_untraced(&self) -> Result<(ChatResponse, HeaderMap), GroqError> {
+        self.preflight()?;
+        let request = self.request_value()?;
+        let response = self.post(&request).await?;
+        if response.status.is_client_error() || response.status.is_server_error() {
+            let status = response.status;
+            let raw_response = response.text().await?;
+            return Err(api_error(status, &raw_response));
+        }
+        let headers = response.headers.clone();
+        let raw_response = response.text().await?;
+        let chat_response = decode::<ChatResponse>(&raw_response)?;
+        self.response_hooks.iter().for_each(|hook| hook(&chat_response));
+        if let Some(usage) = &chat_response.usage {
+            *self.session_usage.lock().unwrap() += usage;
+            #[cfg(feature = "tracing")]
+            tracing::Span::current()
+                .record("prompt_tokens", usage.prompt_tokens)
+                .record("completion_tokens", usage.completion_tokens)
+                .record("total_time", usage.total_time);
+        }
+        Ok((chat_response, headers))
+    }
+
+    /// Sends every chat with at most `concurrency` requests in flight and
+    /// returns the results in the order of `chats`.
+    pub async fn send_batch(chats: Vec<Chat>, concurrency: usize) -> Vec<Result<ChatResponse, GroqError>> {
+        let mut results: Vec<(usize, Result<ChatResponse, GroqError>)> = futures::stream::iter(chats.into_iter().enumerate())
+            .map(|(index, chat)| async move { (index, chat.send().await) })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Sends the request and records the exact bytes exchanged. The record is
+    /// returned for any HTTP status; only transport failures are errors.
+    pub async fn send_audited(&self) -> Result<AuditRecord, GroqError> {
+        self.preflight()?;
+        let request = self.request_value()?;
+        let body = serde_json::to_string(&request)?;
+        let timestamp = SystemTime::now();
+        let response = self.post(&request).await?;
+        let status = response.status.as_u16();
+        let response_bytes = response.bytes().await?;
+        Ok(AuditRecord {
+            request_bytes: body.into_bytes(),
+            response_bytes,
+            status,
+            timestamp,
+        })
+    }
+
+    /// Sends the conversation to `model` without changing this chat's model.
+    pub async fn send_with_model(&self, model: &str) -> Result<ChatResponse, GroqError> {
+        let mut chat = self.clone();
+        chat.set_model(model.to_string());
+        chat.send().await
+    }
+
+    /// Streams the conversation from `model` without changing this chat's model.
+    pub async fn stream_with_model(&self, model: &str) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        let mut chat = self.clone();
+        chat.set_model(model.to_string());
+        chat.stream().await
+    }
+
+    /// Like `send`, but answers identical requests to the same endpoint from an
+    /// in-memory cache. Clones of a `Chat` share the same cache. The cache is
+    /// unbounded and its key does not include the API key; clear it with
+    /// `clear_response_cache`. Copies made with `with_api_key` start empty.
+    pub async fn send_cached(&self) -> Result<CachedResponse, GroqError> {
+        let key = format!("{} {}", self.chat_url(), self.request_json()?);
+        if let Some(response) = self.response_cache.lock().unwrap().get(&key) {
+            return Ok(CachedResponse { response: response.clone(), from_cache: true });
+        }
+        let response = self.send().await?;
+        self.response_cache.lock().unwrap().insert(key, response.clone());
+        Ok(CachedResponse { response, from_cache: false })
+    }
+
+    pub fn clear_response_cache(&mut self) {
+        self.response_cache.lock().unwrap().clear();
+    }
+
+    /// Sends the request and parses the reply content as JSON into `T`. If the
+    /// content is missing or not valid JSON for `T`, `fallback` is returned
+    /// instead. The parse error is logged as a warning with the `tracing`
+    /// feature; without it, nothing is logged. Transport and API errors still
+    /// fail.
+    pub async fn send_json_or<T, F>(&self, fallback: F) -> Result<T, GroqError>
     where
-        D: Deserializer<'de>,
+        T: DeserializeOwned,
+        F: Fn() -> T,
     {
-        // Define a helper with optional delta/message fields.
-        #[derive(Deserialize)]
-        struct ChatChoiceHelper {
-            index: u64,
-            #[serde(default)]
-            delta: Option<ChatMessage>,
-            #[serde(default)]
-            message: Option<ChatMessage>,
-            logprobs: Option<String>,
-            finish_reason: Option<String>,
+        let response = self.send().await?;
+        let content = response.choices.first()
+            .and_then(|choice| choice.message.content.as_deref())
+            .unwrap_or_default();
+        let parsed = serde_json::from_str::<T>(content);
+        #[cfg(feature = "tracing")]
+        if let Err(e) = &parsed {
+            tracing::warn!("Failed to parse JSON content, using fallback: {}", e);
         }
-        
-        let helper = ChatChoiceHelper::deserialize(deserializer)?;
-        let message = helper.delta.or(helper.message)
-            .ok_or_else(|| de::Error::missing_field("delta or message"))?;
-        Ok(ChatChoice {
-            index: helper.index,
-            message,
-            logprobs: helper.logprobs,
-            finish_reason: helper.finish_reason,
+        Ok(parsed.unwrap_or_else(|_| fallback()))
+    }
+
+    /// Sends the conversation and resolves tool calls with `dispatcher` until the
+    /// model answers with plain content. Tool calls of one reply are dispatched
+    /// concurrently. The assistant's tool-call messages and the tool results are
+    /// appended to the history. Malformed or invalid arguments are sent back to
+    /// the model as the tool result. If the dispatcher fails, the reply's tool
+    /// calls are not recorded, so the history stays valid for later requests.
+    /// Gives up after `max_iterations` round trips to guard against tool loops;
+    /// `DEFAULT_MAX_TOOL_ITERATIONS` is a sensible cap. The error then carries
+    /// the conversation so far.
+    pub async fn run_with_tools<F, Fut>(&mut self, dispatcher: F, max_iterations: usize) -> Result<ChatResponse, GroqError>
+    where
+        F: Fn(String, Value) -> Fut,
+        Fut: Future<Output = Result<String, GroqError>>,
+    {
+        for _ in 0..max_iterations {
+            let response = self.send().await?;
+            let Some(choice) = response.choices.first() else {
+                return Ok(response);
+            };
+            let tool_calls = match &choice.message.tool_calls {
+                Some(tool_calls) if !tool_calls.is_empty() => tool_calls.clone(),
+                _ => return Ok(response),
+            };
+            let mut dispatches = Vec::with_capacity(tool_calls.len());
+            for tool_call in &tool_calls {
+                // Invalid arguments go back to the model so it can correct them.
+                let name = &tool_call.function.name;
+                let prepared = serde_json::from_str::<Value>(&tool_call.function.arguments)
+                    .map_err(|e| format!("Invalid JSON arguments for tool {}: {}", name, e))
+                    .and_then(|arguments| {
+                        self.check_tool_arguments(name, &arguments).map_err(|e| e.to_string())?;
+                        Ok(dispatcher(name.clone(), arguments))
+                    });
+                dispatches.push(async move {
+                    match prepared {
+                        Err(e) => Ok(e),
+                        Ok(dispatch) => dispatch.await,
+                    }
+                });
+            }
+            // The tool calls run concurrently; join_all keeps the results in call order.
+            let mut results = Vec::with_capacity(tool_calls.len());
+            for (tool_call, result) in tool_calls.iter().zip(futures::future::join_all(dispatches).await) {
+                results.push((tool_call.id.clone(), result?));
+            }
+            self.append_assistant_tool_calls(&response);
+            self.add_tool_results(&tool_calls, results);
+        }
+        Err(GroqError::MaxToolIterationsExceeded {
+            max_iterations,
+            messages: self.chat_request.messages.clone(),
         })
     }
-}
 
+    /// Validates tool call arguments against the schema of the named tool.
+    #[cfg(feature = "jsonschema")]
+    fn check_tool_arguments(&self, name: &str, arguments: &Value) -> Result<(), GroqError> {
+        match self.chat_request.tools.iter().find(|tool| tool.function.name.as_deref() == Some(name)) {
+            Some(tool) => tool.validate_arguments(arguments),
+            None => Ok(()),
+        }
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatUsage {
-    pub queue_time: f64,
-    pub prompt_tokens: u64,
-    pub prompt_time: f64,
-    pub completion_tokens: u64,
-    pub completion_time: f64,
-    pub total_tokens: u64,
-    pub total_time: f64,
-}
+    #[cfg(not(feature = "jsonschema"))]
+    fn check_tool_arguments(&self, _name: &str, _arguments: &Value) -> Result<(), GroqError> {
+        Ok(())
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatXGroq {
-    pub id: String,
-}
+    /// Appends `user_text` as a user message, sends the conversation and appends
+    /// the reply to the history. Returns the reply's text. If sending fails the
+    /// user message is removed again so the turn can be retried.
+    pub async fn ask(&mut self, user_text: &str) -> Result<String, GroqError> {
+        self.chat_request.messages.push(ChatMessage::new(ChatRole::User, user_text, None));
+        let response = match self.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                self.chat_request.messages.pop();
+                return Err(e);
+            }
+        };
+        self.push_response(&response);
+        Ok(response.assistant_text().unwrap_or_default())
+    }
 
-#[derive(Clone, Serialize, Deserialize, Debug, Error)]
-pub struct ChatError {
-    error: ChatErrorDetails,
-}
+    /// Drops the last assistant message, if any, and sends the conversation
+    /// again. With `bump_seed`, a configured seed is incremented and kept so the
+    /// new reply can differ; otherwise the seed is left as is. The fresh reply is
+    /// appended to the history. If sending fails, the previous reply and seed
+    /// are restored.
+    pub async fn regenerate(&mut self, bump_seed: bool) -> Result<ChatResponse, GroqError> {
+        let previous_reply = match self.chat_request.messages.last() {
+            Some(message) if message.role == ChatRole::Assistant => self.chat_request.messages.pop(),
+            _ => None,
+        };
+        let previous_seed = self.chat_request.seed;
+        if let Some(seed) = previous_seed.filter(|_| bump_seed) {
+            self.chat_request.seed = Some(seed.wrapping_add(1));
+        }
+        let response = match self.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                self.chat_request.messages.extend(previous_reply);
+                self.chat_request.seed = previous_seed;
+                return Err(e);
+            }
+        };
+        self.push_response(&response);
+        Ok(response)
+    }
 
-impl Display for ChatError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Chat error: {}", self.error.message)
+    pub async fn stream(&self) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        self.preflight()?;
+        let mut request = self.request_value()?;
+        request["stream"] = Value::Bool(true);
+        let deadline = self.first_token_timeout
+            .map(|timeout| (timeout, tokio::time::Instant::now() + timeout));
+        let response = match deadline {
+            Some((timeout, deadline)) => tokio::time::timeout_at(deadline, self.post(&request)).await
+                .map_err(|_| GroqError::FirstTokenTimeout(timeout))??,
+            None => self.post(&request).await?,
+        };
+    
+        if response.status.is_client_error() || response.status.is_server_error() {
+            let status = response.status;
+            let raw_response = response.text().await?;
+            return Err(api_error(status, &raw_response));
+        }
+    
+        let body = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout_stream(response.body, idle_timeout).boxed_unpin(),
+            None => response.body,
+        };
+        let stream_reader = StreamReader::new(body);
+        let lines = FramedRead::new(stream_reader, LineDecoder::default())
+            .map(|line| {
+                line.map_err(|e| match e.kind() {
+                    io::ErrorKind::TimedOut => GroqError::Timeout,
+                    _ => GroqError::from(e),
+                })
+                .and_then(|line| line)
+            });
+    
+        let max_stream_chunks = self.max_stream_chunks;
+        let json_stream = parse_sse_lines(lines).scan(0usize, move |received, item| {
+            if *received == usize::MAX {
+                return futures::future::ready(None);
+            }
+            *received += 1;
+            match max_stream_chunks {
+                Some(max) if *received > max => {
+                    *received = usize::MAX;
+                    futures::future::ready(Some(Err(GroqError::StreamChunkLimit(max))))
+                }
+                _ => futures::future::ready(Some(item)),
+            }
+        });
+        let response_hooks = self.response_hooks.clone();
+        let json_stream = json_stream.inspect(move |item| {
+            if let Ok(chunk) = item {
+                response_hooks.iter().for_each(|hook| hook(chunk));
+            }
+        });
+        match deadline {
+            Some((timeout, deadline)) => Ok(first_token_deadline(json_stream.boxed_unpin(), timeout, deadline).boxed_unpin()),
+            None => Ok(json_stream.boxed_unpin()),
+        }
     }
-}
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
-pub struct ChatErrorDetails {
-    message: String,
-    r#type: String,
-    param: Option<String>,
-    code: Option<String>,
-}
\ No newline at end of file
+    /// Like `stream`, but yields typed events: a `Chunk` for every chunk with
+    /// choices, a `Usage` once the usage is reported and `Done` at the end.
+    /// `Done` is only yielded when the stream produced no error.
+    pub async fn stream_events(&self) -> Result<impl Stream<Item = Result<StreamEvent, GroqError>> + Unpin, GroqError> {
+        // `None` marks the end of the stream.
+        let events = self.stream().await?
+            .map(Some)
+            .chain(futures::stream::once(futures::future::ready(None)))
+            .scan(false, |failed, item| {
+                let events = match item {
+                    Some(Ok(chunk)) => {
+                        let usage = chunk.usage.clone().or_else(|| chunk.x_groq.usage.clone());
+                        let mut events = vec![];
+                        if !chunk.choices.is_empty() {
+                            events.push(Ok(StreamEvent::Chunk(chunk)));
+                        }
+                        events.extend(usage.map(|usage| Ok(StreamEvent::Usage(usage))));
+                        events
+                    }
+                    Some(Err(e)) => {
+                        *failed = true;
+                        vec![Err(e)]
+                    }
+                    None if *failed => vec![],
+                    None => vec![Ok(StreamEvent::Done)],
+                };
+                futures::future::ready(Some(futures::stream::iter(events)))
+            })
+            .flatten();
+        Ok(events.boxed_unpin())
+    }
+
+    /// Like `stream`, but retries the whole request per the retry policy when it
+    /// fails with a transient error before any content arrived. Once content has
+    /// been received, errors are passed through so no output is duplicated.
+    pub async fn stream_with_retry(&self) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        let mut attempt = 0;
+        loop {
+            match self.stream_until_content().await {
+                Ok(stream) => return Ok(stream),
+                Err(e) if e.is_transient() && attempt < self.retry_policy.max_retries => {
+                    tokio::time::sleep(self.retry_policy.backoff(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Opens a stream and reads ahead until the first chunk carrying content,
+    /// returning the chunks read so far followed by the rest of the stream.
+    async fn stream_until_content(&self) -> Result<BoxStreamUnpin<Result<ChatResponse, GroqError>>, GroqError> {
+        let mut stream = self.stream().await?;
+        let mut received = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let has_content = chunk.choices.iter()
+                .any(|choice| choice.message.content.as_deref().is_some_and(|content| !content.is_empty()));
+            received.push(Ok(chunk));
+            if has_content {
+                break;
+            }
+        }
+        Ok(futures::stream::iter(received).chain(stream).boxed_unpin())
+    }
+
+    /// Like `stream`, but keeps the content received so far. If the stream fails,
+    /// the error carries that partial text so the reply can be resumed with
+    /// `resume_from_partial`.
+    pub async fn stream_resumable(&self) -> Result<ResumableStream, GroqError> {
+        Ok(ResumableStream {
+            inner: self.stream().await?.boxed_unpin(),
+            accumulated: String::new(),
+        })
+    }
+
+    /// Appends `partial` as an assistant message so the next request continues
+    /// an interrupted reply instead of starting over.
+    pub fn resume_from_partial(&mut self, partial: &str) {
+        self.chat_request.messages.push(ChatMessage::new(ChatRole::Assistant, partial, None));
+    }
+
+    /// Like `stream`, but ends the stream right after the first error instead of
+    /// yielding any further chunks.
+    pub async fn stream_strict(&self) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        let strict_stream = self.stream().await?.scan(false, |failed, item| {
+            if *failed {
+                return futures::future::ready(None);
+            }
+            *failed = item.is_err();
+            futures::future::ready(Some(item))
+        });
+        Ok(strict_stream.boxed_unpin())
+    }
+
+    /// Like `stream`, but splits every chunk into one update per choice, each
+    /// carrying the content accumulated so far for that choice index. Updates
+    /// of the same choice arrive in the order they were streamed.
+    pub async fn stream_by_choice(&self) -> Result<impl Stream<Item = Result<ChoiceDelta, GroqError>> + Unpin, GroqError> {
+        let choice_stream = self.stream().await?
+            .scan(BTreeMap::<u64, String>::new(), |accumulated, item| {
+                let updates = match item {
+                    Ok(chunk) => chunk.choices.into_iter()
+                        .map(|choice| {
+                            let content = choice.message.content.unwrap_or_default();
+                            let text = accumulated.entry(choice.index).or_default();
+                            text.push_str(&content);
+                            Ok(ChoiceDelta {
+                                index: choice.index,
+                                content,
+                                accumulated: text.clone(),
+                                finish_reason: choice.finish_reason,
+                            })
+                        })
+                        .collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                futures::future::ready(Some(futures::stream::iter(updates)))
+            })
+            .flatten();
+        Ok(choice_stream.boxed_unpin())
+    }
+
+    /// Like `stream`, but skips chunks that fail to parse instead of yielding
+    /// an error, so one corrupted chunk does not end the generation. Skipped
+    /// chunks are logged with the `tracing` feature. Other errors are passed
+    /// through.
+    pub async fn stream_lenient(&self) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        let lenient_stream = self.stream().await?.filter_map(|item| {
+            let item = match item {
+                Err(GroqError::Decode { .. }) => {
+                    #[cfg(feature = "tracing")]
+                    if let Err(GroqError::Decode { source, body }) = &item {
+                        tracing::warn!("Skipping unparseable chunk ({}): {}", source, body);
+                    }
+                    None
+                }
+                item => Some(item),
+            };
+            futures::future::ready(item)
+        });
+        Ok(lenient_stream.boxed_unpin())
+    }
+
+    /// Like `stream`, but yields only the text deltas, skipping chunks that carry
+    /// no content. Errors are passed through.
+    pub async fn text_stream(&self) -> Result<impl Stream<Item = Result<String, GroqError>> + Unpin, GroqError> {
+        let text_stream = self.stream().await?.filter_map(|item| {
+            let text = match item {
+                Ok(chunk) => {
+                    let text: String = chunk.choices.into_iter()
+                        .filter_map(|choice| choice.message.content)
+                        .collect();
+                    (!text.is_empty()).then_some(Ok(text))
+                }
+                Err(e) => Some(Err(e)),
+            };
+            futures::future::ready(text)
+        });
+        Ok(text_stream.boxed_unpin())
+    }
+
+    /// Like `stream`, but merges consecutive chunks until their combined content
+    /// reaches `min_chars` characters, handing slow consumers fewer, larger
+    /// chunks. Whatever is buffered is flushed when the stream ends or fails.
+    pub async fn stream_chunked(&self, min_chars: usize) -> Result<impl Stream<Item = Result<ChatResponse, GroqError>> + Unpin, GroqError> {
+        let stream = self.stream().await?.boxed_unpin();
+        let state = (Some(stream), None::<ChatResponse>, None::<GroqError>);
+        let chunked_stream = futures::stream::unfold(state, move |(mut stream, mut buffer, pending_error)| async move {
+            if let Some(e) = pending_error {
+                return Some((Err(e), (stream, None, None)));
+            }
+            loop {
+                let Some(inner) = stream.as_mut() else {
+                    return buffer.take().map(|buffered| (Ok(buffered), (None, None, None)));
+                };
+                match inner.next().await {
+                    Some(Ok(chunk)) => {
+                        let merged = match buffer.take() {
+                            Some(mut buffered) => {
+                                buffered.merge_chunk(chunk);
+                                buffered
+                            }
+                            None => chunk,
+                        };
+                        let chars: usize = merged.choices.iter()
+                            .filter_map(ChatChoice::content)
+                            .map(|content| content.chars().count())
+                            .sum();
+                        if chars >= min_chars {
+                            return Some((Ok(merged), (stream, None, None)));
+                        }
+                        buffer = Some(merged);
+                    }
+                    Some(Err(e)) => return match buffer.take() {
+                        Some(buffered) => Some((Ok(buffered), (stream, None, Some(e)))),
+                        None => Some((Err(e), (stream, None, None))),
+                    },
+                    None => stream = None,
+                }
+            }
+        });
+        Ok(chunked_stream.boxed_unpin())
+    }
+
+    /// Streams the reply to stdout as it arrives and returns the assembled response.
+    pub async fn stream_to_stdout(&self) -> Result<ChatResponse, GroqError> {
+        self.stream_to_writer(&mut io::stdout()).await
+    }
+
+    /// Drives `stream` to completion and returns the concatenated content of
+    /// every chunk.
+    pub async fn stream_to_string(&self) -> Result<String, GroqError> {
+        let mut stream = self.stream().await?;
+        let mut text = String::new();
+        while let Some(chunk) = stream.next().await {
+            for choice in chunk?.choices {
+                if let Some(content) = choice.message.content {
+                    text.push_str(&content);
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    /// Collects the streamed reply while timing the gaps between content deltas.
+    pub async fn stream_timed(&self) -> Result<TimedResponse, GroqError> {
+        let mut stream = self.stream().await?;
+        let mut response: Option<ChatResponse> = None;
+        let mut timestamps = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            let has_content = chunk.choices.iter()
+                .any(|choice| choice.message.content.as_deref().is_some_and(|content| !content.is_empty()));
+            if has_content {
+                timestamps.push(Instant::now());
+            }
+            match response.as_mut() {
+                Some(response) => response.merge_chunk(chunk),
+                None => response = Some(chunk),
+            }
+        }
+        let gaps: Vec<Duration> = timestamps.windows(2)
+            .map(|pair| pair[1].duration_since(pair[0]))
+            .collect();
+        Ok(TimedResponse {
+            response: response.ok_or(GroqError::EmptyStream)?,
+            inter_token: InterTokenStats::from_gaps(&gaps),
+        })
+    }
+
+    /// Writes each content delta to `writer`, flushing after every chunk, and
+    /// returns the assembled response once the stream ends.
+    pub async fn stream_to_writer<W: Write>(&self, writer: &mut W) -> Result<ChatResponse, GroqError> {
+        let mut stream = self.stream().await?;
+        let mut response: Option<ChatResponse> = None;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            for choice in &chunk.choices {
+                if let Some(content) = &choice.message.content {
+                    writer.write_all(content.as_bytes())?;
+                }
+            }
+            writer.flush()?;
+            match response.as_mut() {
+                Some(response) => response.merge_chunk(chunk),
+                None => response = Some(chunk),
+            }
+        }
+        response.ok_or(GroqError::EmptyStream)
+    }
+}
+
+/// Re-encodes a chunk stream as OpenAI-compatible SSE frames, ending with
+/// `data: [DONE]`, so it can be forwarded to clients unchanged.
+pub fn sse_frames<S>(stream: S) -> impl Stream<Item = Result<String, GroqError>>
+where
+    S: Stream<Item = Result<ChatResponse, GroqError>>,
+{
+    stream
+        .map(|chunk| Ok(chunk?.to_sse_frame()?))
+        .chain(futures::stream::once(futures::future::ready(Ok("data: [DONE]\n\n".to_string()))))
+}
+
+/// Ends `body` with a `TimedOut` error when the next piece of it takes longer
+/// than `idle_timeout` to arrive.
+fn idle_timeout_stream(
+    body: BoxStreamUnpin<Result<Bytes, io::Error>>,
+    idle_timeout: Duration,
+) -> impl Stream<Item = Result<Bytes, io::Error>> {
+    futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(idle_timeout, body.next()).await {
+            Ok(Some(item)) => Some((item, Some(body))),
+            Ok(None) => None,
+            Err(_) => {
+                let e = io::Error::new(io::ErrorKind::TimedOut, format!("no data received for {:?}", idle_timeout));
+                Some((Err(e), None))
+            }
+        }
+    })
+}
+
+/// Ends `stream` with `GroqError::FirstTokenTimeout` if no chunk carrying
+/// content arrives before `deadline`. Later chunks are passed through untimed.
+fn first_token_deadline(
+    stream: BoxStreamUnpin<Result<ChatResponse, GroqError>>,
+    timeout: Duration,
+    deadline: tokio::time::Instant,
+) -> impl Stream<Item = Result<ChatResponse, GroqError>> {
+    futures::stream::unfold((Some(stream), Some(deadline)), move |(stream, deadline)| async move {
+        let mut stream = stream?;
+        let Some(deadline) = deadline else {
+            let item = stream.next().await?;
+            return Some((item, (Some(stream), None)));
+        };
+        match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                let has_content = chunk.choices.iter()
+                    .any(|choice| choice.message.content.as_deref().is_some_and(|content| !content.is_empty()));
+                let deadline = (!has_content).then_some(deadline);
+                Some((Ok(chunk), (Some(stream), deadline)))
+            }
+            Ok(Some(Err(e))) => Some((Err(e), (Some(stream), Some(deadline)))),
+            Ok(None) => None,
+            Err(_) => Some((Err(GroqError::FirstTokenTimeout(timeout)), (None, None))),
+        }
+    })
+}
+
+/// Separates `<think>` blocks from the rest of `content`. An unclosed block
+/// runs to the end of the content.
+fn split_thinking(content: &str) -> (String, Vec<String>) {
+    let mut answer = String::new();
+    let mut thinking = vec![];
+    let mut rest = content;
+    while let Some(start) = rest.find("<think>") {
+        answer.push_str(&rest[..start]);
+        let block = &rest[start + "<think>".len()..];
+        let (thought, after) = block.split_once("</think>").unwrap_or((block, ""));
+        thinking.push(thought.trim().to_string());
+        rest = after;
+    }
+    answer.push_str(rest);
+    (answer.trim().to_string(), thinking)
+}
+
+/// Removes a surrounding markdown code fence and commas directly before a
+/// closing bracket, leaving string contents untouched.
+fn repair_json(text: &str) -> String {
+    let mut text = text.trim();
+    if let Some(fenced) = text.strip_prefix("```") {
+        let fenced = fenced.strip_suffix("```").unwrap_or(fenced).trim();
+        // Drop the info string, e.g. `json`, unless the JSON starts on the
+        // opening fence line.
+        text = match fenced.split_once('\n') {
+            Some((info, body)) if !info.trim_start().starts_with(['{', '[']) => body.trim(),
+            _ => fenced,
+        };
+    }
+    let mut repaired = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = text[index + 1..].trim_start().chars().next();
+            if matches!(next, Some('}') | Some(']')) {
+                continue;
+            }
+        }
+        repaired.push(c);
+    }
+    repaired
+}
+
+/// Converts reported seconds to a `Duration`, treating negative or invalid
+/// values as zero.
+fn seconds_to_duration(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or_default()
+}
+
+/// Turns an error response into a `GroqError`. Bodies that are not an API
+/// error, such as a proxy's HTML page, are kept raw together with the status.
+pub(crate) fn api_error(status: StatusCode, body: &str) -> GroqError {
+    match serde_json::from_str::<ChatError>(body) {
+        Ok(error) => GroqError::Api { status: Some(status), error },
+        Err(_) => GroqError::Status {
+            status,
+            body: body.to_string(),
+        },
+    }
+}
+
+/// Deserializes a response body, keeping the raw text on failure so callers can
+/// see what the server actually returned.
+pub(crate) fn decode<T: DeserializeOwned>(body: &str) -> Result<T, GroqError> {
+    serde_json::from_str::<T>(body).map_err(|source| GroqError::Decode {
+        source,
+        body: body.to_string(),
+    })
+}
+
+/// Turns the lines of a server-sent event stream into chat chunks. Comment lines
+/// are ignored and `error` events are surfaced as `Err` items.
+fn parse_sse_lines<S>(lines: S) -> impl Stream<Item = Result<ChatResponse, GroqError>>
+where
+    S: Stream<Item = Result<String, GroqError>>,
+{
+    // A trailing blank line flushes an event the server did not terminate.
+    lines
+        .chain(futures::stream::once(futures::future::ready(Ok(String::new()))))
+        .scan(SseParser::default(), |parser, line_result| {
+            let items = match line_result {
+                Ok(line) => parser.push_line(&line),
+                Err(e) => vec![Err(e)],
+            };
+            futures::future::ready(Some(futures::stream::iter(items)))
+        })
+        .flatten()
+}
+
+/// Splits a byte stream into lines. A line is only decoded once it is complete,
+/// so multibyte characters split across chunks are reassembled, and a line
+/// that is not valid UTF-8 is reported without ending the stream.
+#[derive(Default)]
+struct LineDecoder {
+    next_index: usize,
+}
+
+impl LineDecoder {
+    fn take_line(&mut self, buf: &mut BytesMut, end: usize) -> Result<String, GroqError> {
+        let line = buf.split_to(end);
+        self.next_index = 0;
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Ok(String::from_utf8(line.to_vec())?)
+    }
+}
+
+impl Decoder for LineDecoder {
+    type Item = Result<String, GroqError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match buf[self.next_index..].iter().position(|byte| *byte == b'\n') {
+            Some(offset) => {
+                let end = self.next_index + offset + 1;
+                Ok(Some(self.take_line(buf, end)))
+            }
+            None => {
+                self.next_index = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                let end = buf.len();
+                Ok(Some(self.take_line(buf, end)))
+            }
+        }
+    }
+}
+
+/// Splits a data value that may hold several JSON payloads glued together,
+/// optionally separated by further `data:` prefixes. Anything that does not
+/// parse as a complete JSON value is returned as the last segment.
+fn split_data_segments(value: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let mut values = serde_json::Deserializer::from_str(rest).into_iter::<de::IgnoredAny>();
+        match values.next() {
+            Some(Ok(_)) => {
+                let end = values.byte_offset();
+                segments.push(rest[..end].trim());
+                rest = rest[end..].trim_start();
+                rest = rest.strip_prefix("data:").unwrap_or(rest).trim_start();
+            }
+            _ => {
+                segments.push(rest);
+                break;
+            }
+        }
+    }
+    segments
+}
+
+/// Accumulates the fields of a server-sent event until a blank line dispatches it.
+#[derive(Default)]
+struct SseParser {
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    fn push_line(&mut self, line: &str) -> Vec<Result<ChatResponse, GroqError>> {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            return self.dispatch().into_iter().collect();
+        }
+        if trimmed.starts_with(':') {
+            return vec![];
+        }
+        // Bare JSON lines are accepted as their own event for non-SSE servers.
+        if trimmed.starts_with('{') {
+            return split_data_segments(trimmed).into_iter()
+                .filter_map(|segment| {
+                    self.data.push(segment.to_string());
+                    self.dispatch()
+                })
+                .collect();
+        }
+        let (field, value) = trimmed.split_once(':').unwrap_or((trimmed, ""));
+        match field.trim() {
+            "event" => self.event = Some(value.trim().to_string()),
+            "data" => {
+                // Every complete payload but the last was glued onto this line by
+                // the server and is dispatched on its own.
+                let mut segments = split_data_segments(value);
+                let last = segments.pop();
+                let items = segments.into_iter()
+                    .filter_map(|segment| {
+                        self.data.push(segment.to_string());
+                        self.dispatch()
+                    })
+                    .collect();
+                self.data.extend(last.map(str::to_string));
+                return items;
+            }
+            _ => {}
+        }
+        vec![]
+    }
+
+    fn dispatch(&mut self) -> Option<Result<ChatResponse, GroqError>> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data).join("\n");
+        if data == "[DONE]" {
+            return None;
+        }
+        if event.as_deref() == Some("error") {
+            let chat_error = serde_json::from_str::<ChatError>(&data).unwrap_or_else(|_| ChatError {
+                error: ChatErrorDetails {
+                    message: data,
+                    r#type: "stream_error".to_string(),
+                    param: None,
+                    code: None,
+                },
+            });
+            return Some(Err(GroqError::Api { status: None, error: chat_error }));
+        }
+        Some(decode::<ChatResponse>(&data))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatMessage {
+    #[serde(default = "assistant_role")]
+    pub role: ChatRole,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// The model's reasoning, returned when `reasoning_format` is `parsed`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+}
+
+fn assistant_role() -> ChatRole {
+    ChatRole::Assistant
+}
+
+impl ChatMessage {
+    fn estimated_tokens(&self) -> usize {
+        let content = self.content.as_deref().map_or(0, estimate_tokens);
+        let tool_calls: usize = self.tool_calls.iter().flatten()
+            .map(|tool_call| estimate_tokens(&tool_call.function.name) + estimate_tokens(&tool_call.function.arguments))
+            .sum();
+        content + tool_calls
+    }
+}
+
+/// Collapses each run of whitespace outside ``` code fences into a single
+/// newline, if the run contained one, or a single space otherwise.
+pub fn compact_whitespace(text: &str) -> String {
+    let mut compacted = String::with_capacity(text.len());
+    for (i, part) in text.split("```").enumerate() {
+        if i > 0 {
+            compacted.push_str("```");
+        }
+        // Odd parts sit between an opening and a closing fence.
+        if i % 2 == 1 {
+            compacted.push_str(part);
+            continue;
+        }
+        let mut pending: Option<char> = None;
+        for c in part.chars() {
+            if c.is_whitespace() {
+                if c == '\n' || pending.is_none() {
+                    pending = Some(if c == '\n' { '\n' } else { ' ' });
+                }
+                continue;
+            }
+            if let Some(whitespace) = pending.take() {
+                compacted.push(whitespace);
+            }
+            compacted.push(c);
+        }
+        if let Some(whitespace) = pending {
+            compacted.push(whitespace);
+        }
+    }
+    compacted
+}
+
+/// Estimates the number of tokens in `text` at roughly four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct ConversationSummary {
+    pub message_count: usize,
+    pub role_counts: BTreeMap<ChatRole, usize>,
+    pub model: String,
+    pub total_tool_calls: usize,
+    pub estimated_tokens: usize,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: ToolType,
+    pub function: ToolCallFunction,
+}
+
+impl ToolCall {
+    /// Deserializes the JSON arguments string into `T`.
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_str(&self.function.arguments)
+    }
+
+    pub fn arguments_value(&self) -> Result<Value, serde_json::Error> {
+        self.parse_arguments()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ToolCallFunction {
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: &str, tool_call_id: Option<String>) -> Self {
+        Self {
+            role,
+            content: Some(content.to_string()),
+            tool_calls: None,
+            tool_call_id,
+            reasoning: None,
+        }
+    }
+
+    /// Builds a tool result message whose content is `value` as compact JSON.
+    pub fn tool_json(value: Value, tool_call_id: String) -> Self {
+        Self::new(ChatRole::Tool, &value.to_string(), Some(tool_call_id))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(from = "ResponseFormatRepr", into = "ResponseFormatRepr")]
+pub enum ChatResponseFormat {
+    JsonObject,
+    JsonArray,
+    Text,
+    /// Structured outputs: the reply must conform to `schema`.
+    JsonSchema {
+        name: String,
+        schema: Value,
+        strict: bool,
+    },
+}
+
+impl Display for ChatResponseFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatResponseFormat::JsonObject => write!(f, r#"{{ "type": "json_object" }}"#),
+            ChatResponseFormat::JsonArray => write!(f, r#"{{ "type": "json_array" }}"#),
+            ChatResponseFormat::Text => write!(f, r#"{{ "type": "text" }}"#),
+            ChatResponseFormat::JsonSchema { .. } => {
+                let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+                write!(f, "{}", json)
+            }
+        }
+    }
+}
+
+/// The wire format of `ChatResponseFormat`, e.g. `{"type":"json_object"}`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseFormatRepr {
+    JsonObject,
+    JsonArray,
+    Text,
+    JsonSchema { json_schema: JsonSchemaRepr },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct JsonSchemaRepr {
+    name: String,
+    schema: Value,
+    #[serde(default)]
+    strict: bool,
+}
+
+impl From<ResponseFormatRepr> for ChatResponseFormat {
+    fn from(repr: ResponseFormatRepr) -> Self {
+        match repr {
+            ResponseFormatRepr::JsonObject => ChatResponseFormat::JsonObject,
+            ResponseFormatRepr::JsonArray => ChatResponseFormat::JsonArray,
+            ResponseFormatRepr::Text => ChatResponseFormat::Text,
+            ResponseFormatRepr::JsonSchema { json_schema } => ChatResponseFormat::JsonSchema {
+                name: json_schema.name,
+                schema: json_schema.schema,
+                strict: json_schema.strict,
+            },
+        }
+    }
+}
+
+impl From<ChatResponseFormat> for ResponseFormatRepr {
+    fn from(format: ChatResponseFormat) -> Self {
+        match format {
+            ChatResponseFormat::JsonObject => ResponseFormatRepr::JsonObject,
+            ChatResponseFormat::JsonArray => ResponseFormatRepr::JsonArray,
+            ChatResponseFormat::Text => ResponseFormatRepr::Text,
+            ChatResponseFormat::JsonSchema { name, schema, strict } => ResponseFormatRepr::JsonSchema {
+                json_schema: JsonSchemaRepr { name, schema, strict },
+            },
+        }
+    }
+}
+
+/// A service tier. Tiers this crate does not know yet are kept as `Other`, so
+/// responses naming them still decode.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(from = "String", into = "String")]
+pub enum ChatServiceTier {
+    OnDemand,
+    Auto,
+    Flex,
+    Other(String),
+}
+
+impl From<String> for ChatServiceTier {
+    fn from(service_tier: String) -> Self {
+        match service_tier.as_str() {
+            "on_demand" => ChatServiceTier::OnDemand,
+            "auto" => ChatServiceTier::Auto,
+            "flex" => ChatServiceTier::Flex,
+            _ => ChatServiceTier::Other(service_tier),
+        }
+    }
+}
+
+impl From<ChatServiceTier> for String {
+    fn from(service_tier: ChatServiceTier) -> Self {
+        service_tier.to_string()
+    }
+}
+
+impl Display for ChatServiceTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatServiceTier::OnDemand => write!(f, "on_demand"),
+            ChatServiceTier::Auto => write!(f, "auto"),
+            ChatServiceTier::Flex => write!(f, "flex"),
+            ChatServiceTier::Other(service_tier) => write!(f, "{}", service_tier),
+        }
+    }
+}
+
+/// The persistable state of a `Chat`, see `Chat::export_session`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub request: ChatRequest,
+    pub base_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    #[serde(default)]
+    pub session_usage: ChatUsage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assistant_prefill: Option<String>,
+    #[serde(default = "default_true")]
+    pub include_reasoning_in_history: bool,
+    #[serde(default)]
+    pub compact_whitespace: bool,
+    #[serde(default)]
+    pub serialize_empty_tools: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_message_tokens: Option<usize>,
+}
+
+/// What a model is known to support. Unset fields mean no restriction is known.
+#[derive(Clone, Debug, Default)]
+pub struct ModelCapabilities {
+    pub context_window: Option<u32>,
+    pub service_tiers: Option<Vec<ChatServiceTier>>,
+}
+
+/// Capabilities per model name, filled in by the caller.
+#[derive(Clone, Debug, Default)]
+pub struct CapabilityTable {
+    models: HashMap<String, ModelCapabilities>,
+}
+
+impl CapabilityTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, model: &str, capabilities: ModelCapabilities) {
+        self.models.insert(model.to_string(), capabilities);
+    }
+
+    pub fn get(&self, model: &str) -> Option<&ModelCapabilities> {
+        self.models.get(model)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningFormat {
+    Parsed,
+    Raw,
+    Hidden,
+}
+
+impl Display for ReasoningFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReasoningFormat::Parsed => write!(f, "parsed"),
+            ReasoningFormat::Raw => write!(f, "raw"),
+            ReasoningFormat::Hidden => write!(f, "hidden"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    frequency_penalty: f32,
+    max_completion_tokens: Option<u32>,
+    #[serde(default = "default_true")]
+    parallel_tool_calls: bool,
+    #[serde(default)]
+    presence_penalty: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_format: Option<ReasoningFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ChatResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_tier: Option<ChatServiceTier>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default = "default_one")]
+    temperature: f32,
+    #[serde(default = "default_one")]
+    top_p: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<ToolChoice>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    /// Parameters without typed support, merged into the top level of the body.
+    #[serde(flatten)]
+    extra_params: BTreeMap<String, Value>,
+}
+
+// Defaults for fields a saved request may omit, matching `ChatRequest::new`.
+fn default_true() -> bool {
+    true
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+impl ChatRequest {
+    /// The body keys backed by a typed field.
+    const TYPED_PARAMS: &'static [&'static str] = &[
+        "model",
+        "messages",
+        "frequency_penalty",
+        "max_completion_tokens",
+        "parallel_tool_calls",
+        "presence_penalty",
+        "reasoning_format",
+        "response_format",
+        "seed",
+        "service_tier",
+        "stream",
+        "temperature",
+        "top_p",
+        "tool_choice",
+        "tools",
+        "user",
+    ];
+
+    pub fn new(model: String, messages: Vec<ChatMessage>) -> Self {
+        Self { 
+            model,
+            messages,
+            frequency_penalty: 0.0,
+            max_completion_tokens: None,
+            parallel_tool_calls: true,
+            presence_penalty: 0.0,
+            reasoning_format: None,
+            response_format: None,
+            seed: None,
+            service_tier: None,
+            stream: false,
+            temperature: 1.0,
+            top_p: 1.0,
+            tool_choice: None,
+            tools: vec![],
+            user: None,
+            extra_params: BTreeMap::new(),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    pub fn frequency_penalty(&self) -> f32 {
+        self.frequency_penalty
+    }
+
+    pub fn max_completion_tokens(&self) -> Option<u32> {
+        self.max_completion_tokens
+    }
+
+    pub fn parallel_tool_calls(&self) -> bool {
+        self.parallel_tool_calls
+    }
+
+    pub fn presence_penalty(&self) -> f32 {
+        self.presence_penalty
+    }
+
+    pub fn reasoning_format(&self) -> Option<&ReasoningFormat> {
+        self.reasoning_format.as_ref()
+    }
+
+    pub fn response_format(&self) -> Option<&ChatResponseFormat> {
+        self.response_format.as_ref()
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn service_tier(&self) -> Option<&ChatServiceTier> {
+        self.service_tier.as_ref()
+    }
+
+    pub fn stream(&self) -> bool {
+        self.stream
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn top_p(&self) -> f32 {
+        self.top_p
+    }
+
+    pub fn tool_choice(&self) -> Option<&ToolChoice> {
+        self.tool_choice.as_ref()
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn extra_params(&self) -> &BTreeMap<String, Value> {
+        &self.extra_params
+    }
+
+    /// Serializes the request with object keys in sorted order, so equal
+    /// requests always produce the same string.
+    pub fn canonical_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&serde_json::to_value(self)?)
+    }
+}
+
+/// Requests compare and hash by their canonical JSON, so float fields are
+/// compared by their serialized value rather than with float semantics. A
+/// request that fails to serialize equals no other request.
+impl PartialEq for ChatRequest {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.canonical_json(), other.canonical_json()) {
+            (Ok(json), Ok(other_json)) => json == other_json,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ChatRequest {}
+
+impl Hash for ChatRequest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical_json().ok().hash(state);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceValue {
+    None,
+    Auto,
+    Required,
+}
+
+/// Represents the object variant for tool choice.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ToolChoiceObject {
+    /// The type of the tool. Currently, only "function" is supported.
+    #[serde(rename = "type")]
+    pub tool_type: ToolType,
+    /// The tool details.
+    pub function: ToolFunction,
+}
+
+/// Represents the tool details.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ToolFunction {
+    /// The name of the function to call.
+    pub name: Option<String>,
+}
+
+/// An untagged enum that can either be a string (for simple values) or an object (for a specific tool).
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Value(ToolChoiceValue),
+    Object(ToolChoiceObject),
+}
+
+impl ToolChoice {
+    /// Forces the model to call the function named `name`.
+    pub fn function(name: &str) -> Self {
+        ToolChoice::Object(ToolChoiceObject {
+            tool_type: ToolType::Function,
+            function: ToolFunction { name: Some(name.to_string()) },
+        })
+    }
+
+    pub fn auto() -> Self {
+        ToolChoice::Value(ToolChoiceValue::Auto)
+    }
+
+    pub fn none() -> Self {
+        ToolChoice::Value(ToolChoiceValue::None)
+    }
+
+    pub fn required() -> Self {
+        ToolChoice::Value(ToolChoiceValue::Required)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tool {
+    /// A description of what the function does.
+    pub function: Function,
+    /// The type of the tool. Currently, only "function" is supported.
+    #[serde(rename = "type")]
+    pub tool_type: ToolType,
+}
+
+impl Tool {
+    /// Builds a function tool whose parameters schema is derived from `T`, so
+    /// the schema cannot drift from the type the arguments are parsed into.
+    #[cfg(feature = "schemars")]
+    pub fn from_schema<T: schemars::JsonSchema>(name: &str, description: &str) -> Tool {
+        let mut parameters = schemars::schema_for!(T).to_value();
+        if let Some(parameters) = parameters.as_object_mut() {
+            // The meta-schema reference is noise to the model.
+            parameters.remove("$schema");
+        }
+        Tool {
+            function: Function {
+                description: Some(description.to_string()),
+                name: Some(name.to_string()),
+                parameters: Some(parameters),
+            },
+            tool_type: ToolType::Function,
+        }
+    }
+
+    /// Checks `arguments` against the function's `parameters` schema. Tools
+    /// without parameters accept any arguments.
+    #[cfg(feature = "jsonschema")]
+    pub fn validate_arguments(&self, arguments: &Value) -> Result<(), GroqError> {
+        let Some(schema) = &self.function.parameters else {
+            return Ok(());
+        };
+        let tool = self.function.name.clone().unwrap_or_default();
+        let validator = jsonschema::validator_for(schema).map_err(|e| GroqError::InvalidToolArguments {
+            tool: tool.clone(),
+            errors: vec![format!("invalid parameters schema: {}", e)],
+        })?;
+        let errors: Vec<String> = validator.iter_errors(arguments)
+            .map(|e| format!("{} at '{}'", e, e.instance_path))
+            .collect();
+        if !errors.is_empty() {
+            return Err(GroqError::InvalidToolArguments { tool, errors });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Function {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The name of the function to be called. Must be a-z, A-Z, 0-9, or contain underscores and dashes, with a maximum length of 64.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The parameters the function accepts, described as a JSON Schema object.
+    /// Omitting parameters defines a function with an empty parameter list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+/// Builds the JSON Schema for `Function::parameters`: an object with the added
+/// properties and the list of required ones.
+#[derive(Debug, Clone, Default)]
+pub struct ParametersBuilder {
+    properties: serde_json::Map<String, Value>,
+    required: Vec<String>,
+}
+
+impl ParametersBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_string_param(self, name: &str, description: &str, required: bool) -> Self {
+        self.add_param(name, serde_json::json!({ "type": "string", "description": description }), required)
+    }
+
+    pub fn add_number_param(self, name: &str, description: &str, required: bool) -> Self {
+        self.add_param(name, serde_json::json!({ "type": "number", "description": description }), required)
+    }
+
+    pub fn add_integer_param(self, name: &str, description: &str, required: bool) -> Self {
+        self.add_param(name, serde_json::json!({ "type": "integer", "description": description }), required)
+    }
+
+    pub fn add_boolean_param(self, name: &str, description: &str, required: bool) -> Self {
+        self.add_param(name, serde_json::json!({ "type": "boolean", "description": description }), required)
+    }
+
+    /// Adds a string parameter restricted to `values`.
+    pub fn add_enum_param(self, name: &str, description: &str, values: &[&str], required: bool) -> Self {
+        self.add_param(name, serde_json::json!({ "type": "string", "description": description, "enum": values }), required)
+    }
+
+    /// Adds a parameter described by an arbitrary `schema`, e.g. an array or a
+    /// nested object.
+    pub fn add_param(mut self, name: &str, schema: Value, required: bool) -> Self {
+        self.properties.insert(name.to_string(), schema);
+        self.required.retain(|existing| existing != name);
+        if required {
+            self.required.push(name.to_string());
+        }
+        self
+    }
+
+    pub fn build(self) -> Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": self.properties,
+            "required": self.required,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ToolType {
+    #[serde(rename = "function")]
+    Function
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")] 
+pub enum ChatRole {
+    User,
+    Assistant,
+    System,
+    Tool,
+}
+
+impl FromStr for ChatRole {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "user" => Ok(ChatRole::User),
+            "assistant" => Ok(ChatRole::Assistant),
+            "system" => Ok(ChatRole::System),
+            "tool" => Ok(ChatRole::Tool),
+            _ => Err(format!("Unknown chat role: {}", role)),
+        }
+    }
+}
+
+impl TryFrom<&str> for ChatRole {
+    type Error = String;
+
+    fn try_from(role: &str) -> Result<Self, Self::Error> {
+        role.parse()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatResponse {
+    pub id: String,
+    pub object: ObjectType,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChoice>,
+    pub usage: Option<ChatUsage>,
+    pub system_fingerprint: String,
+    pub x_groq: ChatXGroq,
+    /// The service tier that actually handled the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<ChatServiceTier>,
+}
+
+impl ChatResponse {
+    /// Returns the first choice's content if it was written by the assistant.
+    pub fn assistant_text(&self) -> Option<String> {
+        let message = &self.choices.first()?.message;
+        if message.role != ChatRole::Assistant {
+            return None;
+        }
+        message.content.clone()
+    }
+
+    /// Returns the first choice's content, or `None` when there are no choices or
+    /// the first one carries no content, as in a role-only stream chunk.
+    pub fn content(&self) -> Option<&str> {
+        self.choices.first()?.content()
+    }
+
+    /// Returns the first choice's content with any `<think>...</think>` blocks,
+    /// as emitted inline with the `raw` reasoning format, removed.
+    pub fn content_without_thinking(&self) -> Option<String> {
+        let (answer, _) = split_thinking(self.content()?);
+        Some(answer)
+    }
+
+    /// Returns the text inside the first choice's `<think>` blocks, or `None`
+    /// when the content has none.
+    pub fn thinking(&self) -> Option<String> {
+        let (_, thinking) = split_thinking(self.content()?);
+        (!thinking.is_empty()).then(|| thinking.join("\n"))
+    }
+
+    /// Deserializes the first choice's content, as returned in JSON mode, into `T`.
+    pub fn parse_json_content<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        let content = self.choices.first()
+            .and_then(|choice| choice.message.content.as_deref())
+            .ok_or_else(|| de::Error::custom("response has no content"))?;
+        serde_json::from_str(content)
+    }
+
+    /// Like `parse_json_content`, but if the content does not parse as is, strips
+    /// markdown code fences and trailing commas and tries again.
+    pub fn parse_json_content_lenient<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        let content = self.choices.first()
+            .and_then(|choice| choice.message.content.as_deref())
+            .ok_or_else(|| de::Error::custom("response has no content"))?;
+        serde_json::from_str(content).or_else(|_| serde_json::from_str(&repair_json(content)))
+    }
+
+    /// Returns true when tools were expected but the model stopped with a plain
+    /// text answer instead of calling one.
+    pub fn answered_without_tools(&self, expected_tools: &[&str]) -> bool {
+        if expected_tools.is_empty() {
+            return false;
+        }
+        let Some(choice) = self.choices.first() else {
+            return false;
+        };
+        let has_tool_calls = choice.message.tool_calls.as_ref().is_some_and(|tool_calls| !tool_calls.is_empty());
+        let has_content = choice.message.content.as_ref().is_some_and(|content| !content.is_empty());
+        choice.finish_reason.as_deref() == Some("stop") && has_content && !has_tool_calls
+    }
+
+    pub fn total_tokens(&self) -> Option<u64> {
+        self.usage.as_ref().map(|usage| usage.total_tokens)
+    }
+
+    pub fn prompt_tokens(&self) -> Option<u64> {
+        self.usage.as_ref().map(|usage| usage.prompt_tokens)
+    }
+
+    pub fn completion_tokens(&self) -> Option<u64> {
+        self.usage.as_ref().map(|usage| usage.completion_tokens)
+    }
+
+    /// Share of `context_window` used by this exchange's total tokens. Returns 0.0
+    /// when usage is missing or the window is zero.
+    pub fn context_utilization(&self, context_window: u32) -> f64 {
+        match &self.usage {
+            Some(usage) if context_window > 0 => usage.total_tokens as f64 / context_window as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// True when this is a streamed `chat.completion.chunk` carrying deltas
+    /// rather than a complete `chat.completion`.
+    pub fn is_chunk(&self) -> bool {
+        self.object == ObjectType::ChatCompletionChunk
+    }
+
+    /// Iterates over the message of every choice, in choice order.
+    pub fn messages(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.choices.iter().map(|choice| &choice.message)
+    }
+
+    pub fn into_messages(self) -> impl Iterator<Item = ChatMessage> {
+        self.choices.into_iter().map(|choice| choice.message)
+    }
+
+    /// Returns the tool calls of every choice, in choice order.
+    pub fn all_tool_calls(&self) -> Vec<&ToolCall> {
+        self.choices.iter()
+            .flat_map(|choice| choice.message.tool_calls.iter().flatten())
+            .collect()
+    }
+
+    /// True when any choice carries at least one tool call.
+    pub fn has_tool_calls(&self) -> bool {
+        !self.all_tool_calls().is_empty()
+    }
+
+    /// True when the first choice was cut off by the content filter.
+    pub fn was_filtered(&self) -> bool {
+        self.first_finish_reason() == Some("content_filter")
+    }
+
+    /// True when the first choice stopped because it hit the token limit.
+    pub fn was_truncated(&self) -> bool {
+        self.first_finish_reason() == Some("length")
+    }
+
+    /// True when a reasoning model used up its token budget while still
+    /// reasoning, leaving no answer. Retrying with a larger budget may help.
+    pub fn reasoning_only(&self) -> bool {
+        let Some(choice) = self.choices.first() else {
+            return false;
+        };
+        let has_reasoning = choice.message.reasoning.as_ref().is_some_and(|reasoning| !reasoning.trim().is_empty());
+        let has_content = choice.message.content.as_ref().is_some_and(|content| !content.trim().is_empty());
+        self.was_truncated() && has_reasoning && !has_content
+    }
+
+    fn first_finish_reason(&self) -> Option<&str> {
+        self.choices.first()?.finish_reason.as_deref()
+    }
+
+    /// Renders this response as an OpenAI-compatible `data: {json}` SSE frame,
+    /// with each choice's message sent as a `delta`.
+    pub fn to_sse_frame(&self) -> Result<String, serde_json::Error> {
+        let mut chunk = serde_json::to_value(self)?;
+        if let Some(choices) = chunk.get_mut("choices").and_then(Value::as_array_mut) {
+            for choice in choices.iter_mut().filter_map(Value::as_object_mut) {
+                if let Some(message) = choice.remove("message") {
+                    choice.insert("delta".to_string(), message);
+                }
+            }
+        }
+        Ok(format!("data: {}\n\n", serde_json::to_string(&chunk)?))
+    }
+
+    /// Folds a streamed chunk into this response, appending content and tool
+    /// calls per choice index and keeping the latest finish reason and usage.
+    fn merge_chunk(&mut self, chunk: ChatResponse) {
+        for chunk_choice in chunk.choices {
+            match self.choices.iter_mut().find(|choice| choice.index == chunk_choice.index) {
+                Some(choice) => {
+                    if let Some(content) = chunk_choice.message.content {
+                        choice.message.content.get_or_insert_with(String::new).push_str(&content);
+                    }
+                    if let Some(reasoning) = chunk_choice.message.reasoning {
+                        choice.message.reasoning.get_or_insert_with(String::new).push_str(&reasoning);
+                    }
+                    if let Some(tool_calls) = chunk_choice.message.tool_calls {
+                        choice.message.tool_calls.get_or_insert_with(Vec::new).extend(tool_calls);
+                    }
+                    if chunk_choice.finish_reason.is_some() {
+                        choice.finish_reason = chunk_choice.finish_reason;
+                    }
+                }
+                None => self.choices.push(chunk_choice),
+            }
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage;
+        }
+    }
+}
+
+/// The `object` field of a response, telling a full completion from a chunk.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum ObjectType {
+    ChatCompletion,
+    ChatCompletionChunk,
+    Other(String),
+}
+
+impl From<String> for ObjectType {
+    fn from(object: String) -> Self {
+        match object.as_str() {
+            "chat.completion" => ObjectType::ChatCompletion,
+            "chat.completion.chunk" => ObjectType::ChatCompletionChunk,
+            _ => ObjectType::Other(object),
+        }
+    }
+}
+
+impl From<ObjectType> for String {
+    fn from(object: ObjectType) -> Self {
+        object.to_string()
+    }
+}
+
+impl Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectType::ChatCompletion => write!(f, "chat.completion"),
+            ObjectType::ChatCompletionChunk => write!(f, "chat.completion.chunk"),
+            ObjectType::Other(object) => write!(f, "{}", object),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct ChatChoice {
+    pub index: u64,
+    pub message: ChatMessage,
+    pub logprobs: Option<String>,
+    pub finish_reason: Option<String>,
+}
+impl ChatChoice {
+    /// Returns the choice's message, including any tool calls, ready to be
+    /// appended to a conversation.
+    pub fn to_message(&self) -> ChatMessage {
+        self.message.clone()
+    }
+
+    /// Returns the message or delta content, or `None` for chunks that carry
+    /// only a role or tool calls.
+    pub fn content(&self) -> Option<&str> {
+        self.message.content.as_deref()
+    }
+}
+
+impl<'de> Deserialize<'de> for ChatChoice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Define a helper with optional delta/message fields.
+        #[derive(Deserialize)]
+        struct ChatChoiceHelper {
+            index: u64,
+            #[serde(default)]
+            delta: Option<ChatMessage>,
+            #[serde(default)]
+            message: Option<ChatMessage>,
+            logprobs: Option<String>,
+            finish_reason: Option<String>,
+        }
+        
+        let helper = ChatChoiceHelper::deserialize(deserializer)?;
+        // Terminal and usage chunks may carry choices with neither field, which
+        // are read as an empty assistant message. Deltas after the first usually
+        // omit the role, which then defaults to assistant as well.
+        let message = helper.delta.or(helper.message).unwrap_or(ChatMessage {
+            role: ChatRole::Assistant,
+            content: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+        });
+        Ok(ChatChoice {
+            index: helper.index,
+            message,
+            logprobs: helper.logprobs,
+            finish_reason: helper.finish_reason,
+        })
+    }
+}
+
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ChatUsage {
+    pub queue_time: f64,
+    pub prompt_tokens: u64,
+    pub prompt_time: f64,
+    pub completion_tokens: u64,
+    pub completion_time: f64,
+    pub total_tokens: u64,
+    pub total_time: f64,
+}
+
+impl ChatUsage {
+    /// Completion tokens generated per second, or `None` when no completion
+    /// time was reported.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        if self.completion_time <= 0.0 {
+            return None;
+        }
+        Some(self.completion_tokens as f64 / self.completion_time)
+    }
+
+    /// Seconds spent queued plus the seconds spent processing the request.
+    pub fn total_latency(&self) -> f64 {
+        self.queue_time + self.total_time
+    }
+
+    /// Time the request waited in the queue before processing started.
+    pub fn queue_latency(&self) -> Duration {
+        seconds_to_duration(self.queue_time)
+    }
+
+    /// Time spent processing the prompt and generating the completion.
+    pub fn generation_latency(&self) -> Duration {
+        seconds_to_duration(self.prompt_time + self.completion_time)
+    }
+
+    /// Cost in dollars of this usage for `model`, or `None` if the model has no
+    /// entry in `prices`.
+    pub fn estimated_cost(&self, model: &str, prices: &PriceTable) -> Option<f64> {
+        let (prompt_price, completion_price) = prices.get(model)?;
+        Some((self.prompt_tokens as f64 * prompt_price + self.completion_tokens as f64 * completion_price) / 1_000_000.0)
+    }
+}
+
+/// Dollar prices per million prompt and completion tokens, per model name.
+#[derive(Clone, Debug, Default)]
+pub struct PriceTable {
+    models: HashMap<String, (f64, f64)>,
+}
+
+impl PriceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, model: &str, prompt_price: f64, completion_price: f64) {
+        self.models.insert(model.to_string(), (prompt_price, completion_price));
+    }
+
+    /// Returns `(prompt_price, completion_price)` for `model`.
+    pub fn get(&self, model: &str) -> Option<(f64, f64)> {
+        self.models.get(model).copied()
+    }
+}
+
+impl AddAssign<&ChatUsage> for ChatUsage {
+    fn add_assign(&mut self, other: &ChatUsage) {
+        self.queue_time += other.queue_time;
+        self.prompt_tokens += other.prompt_tokens;
+        self.prompt_time += other.prompt_time;
+        self.completion_tokens += other.completion_tokens;
+        self.completion_time += other.completion_time;
+        self.total_tokens += other.total_tokens;
+        self.total_time += other.total_time;
+    }
+}
+
+/// The exact wire bytes of one request/response exchange.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub request_bytes: Vec<u8>,
+    pub response_bytes: Vec<u8>,
+    pub status: u16,
+    /// When the request was sent.
+    pub timestamp: SystemTime,
+}
+
+impl AuditRecord {
+    /// Decodes the recorded response body.
+    pub fn response(&self) -> Result<ChatResponse, GroqError> {
+        decode(&String::from_utf8_lossy(&self.response_bytes))
+    }
+}
+
+/// One choice's share of a streamed chunk, see `Chat::stream_by_choice`.
+#[derive(Clone, Debug)]
+pub struct ChoiceDelta {
+    pub index: u64,
+    /// The content this chunk added to the choice.
+    pub content: String,
+    /// All content of the choice received so far, including `content`.
+    pub accumulated: String,
+    pub finish_reason: Option<String>,
+}
+
+/// A chat stream that accumulates the streamed content. Errors are wrapped in
+/// `GroqError::StreamInterrupted` together with the text received before them.
+pub struct ResumableStream {
+    inner: BoxStreamUnpin<Result<ChatResponse, GroqError>>,
+    accumulated: String,
+}
+
+impl ResumableStream {
+    /// The content received so far.
+    pub fn accumulated(&self) -> &str {
+        &self.accumulated
+    }
+}
+
+impl Stream for ResumableStream {
+    type Item = Result<ChatResponse, GroqError>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                for choice in &chunk.choices {
+                    if let Some(content) = &choice.message.content {
+                        this.accumulated.push_str(content);
+                    }
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(GroqError::StreamInterrupted {
+                partial: this.accumulated.clone(),
+                source: Box::new(e),
+            }))),
+            other => other,
+        }
+    }
+}
+
+/// Rate limit state parsed from the `x-ratelimit-*` and `retry-after` headers.
+/// Fields are `None` when the header was absent or unparseable.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimitInfo {
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<Duration>,
+    pub reset_tokens: Option<Duration>,
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitInfo {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let number = |name: &str| header(name).and_then(|value| value.trim().parse().ok());
+        let duration = |name: &str| header(name).and_then(parse_reset_duration);
+        Self {
+            limit_requests: number("x-ratelimit-limit-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_requests: duration("x-ratelimit-reset-requests"),
+            reset_tokens: duration("x-ratelimit-reset-tokens"),
+            retry_after: duration("retry-after"),
+        }
+    }
+}
+
+/// Parses reset durations such as `2m59.56s`, `7.66s`, `120ms` or a bare
+/// number of seconds.
+fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let number: f64 = rest[..number_end].parse().ok()?;
+        rest = &rest[number_end..];
+        let unit_end = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len());
+        let seconds_per_unit = match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        total += number * seconds_per_unit;
+        rest = &rest[unit_end..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// How often and how patiently failed requests are retried. The delay doubles
+/// after every attempt, starting at `initial_backoff`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub response: ChatResponse,
+    /// Whether the response was served from the cache instead of the API.
+    pub from_cache: bool,
+}
+
+/// A streamed response together with the timing of its content deltas.
+#[derive(Clone, Debug)]
+pub struct TimedResponse {
+    pub response: ChatResponse,
+    /// `None` when fewer than two content deltas arrived.
+    pub inter_token: Option<InterTokenStats>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InterTokenStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub p95: Duration,
+}
+
+impl InterTokenStats {
+    /// Computes the statistics from the gaps between consecutive deltas.
+    pub fn from_gaps(gaps: &[Duration]) -> Option<Self> {
+        if gaps.is_empty() {
+            return None;
+        }
+        let mut sorted = gaps.to_vec();
+        sorted.sort();
+        // Nearest-rank percentile.
+        let p95_rank = (sorted.len() * 95).div_ceil(100).max(1);
+        Some(Self {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p95: sorted[p95_rank - 1],
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatXGroq {
+    pub id: String,
+    /// Usage reported with the final chunk of a stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ChatUsage>,
+}
+
+/// An item of `Chat::stream_events`.
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum StreamEvent {
+    /// A chunk carrying at least one choice.
+    Chunk(ChatResponse),
+    /// The token usage of the whole generation, reported once near the end.
+    Usage(ChatUsage),
+    /// The stream finished without error.
+    Done,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Error)]
+pub struct ChatError {
+    error: ChatErrorDetails,
+}
+
+impl Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Chat error: {}", self.error.message)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ChatErrorDetails {
+    message: String,
+    r#type: String,
+    param: Option<String>,
+    code: Option<String>,
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A status, the body chunks and whether the body stalls after them.
+    type CannedResponse = (StatusCode, Vec<Bytes>, bool);
+
+    /// Serves canned responses in order and records every request it receives.
+    #[derive(Clone, Default)]
+    struct MockHttpClient {
+        responses: Arc<Mutex<VecDeque<CannedResponse>>>,
+        requests: Arc<Mutex<Vec<HttpRequest>>>,
+    }
+
+    impl MockHttpClient {
+        fn respond(&self, status: StatusCode, chunks: Vec<&[u8]>) -> &Self {
+            let chunks = chunks.into_iter().map(Bytes::copy_from_slice).collect();
+            self.responses.lock().unwrap().push_back((status, chunks, false));
+            self
+        }
+
+        fn requests(&self) -> Vec<HttpRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// Like `respond`, but the body never ends after `chunks`.
+        fn respond_then_stall(&self, status: StatusCode, chunks: Vec<&[u8]>) -> &Self {
+            let chunks = chunks.into_iter().map(Bytes::copy_from_slice).collect();
+            self.responses.lock().unwrap().push_back((status, chunks, true));
+            self
+        }
+    }
+
+    impl HttpClient for MockHttpClient {
+        fn post(&self, request: HttpRequest) -> crate::http::HttpFuture<'_> {
+            self.requests.lock().unwrap().push(request);
+            let (status, chunks, stall) = self.responses.lock().unwrap().pop_front().expect("no canned response left");
+            Box::pin(async move {
+                let body = futures::stream::iter(chunks.into_iter().map(Ok));
+                let body = match stall {
+                    true => body.chain(futures::stream::pending()).boxed_unpin(),
+                    false => body.boxed_unpin(),
+                };
+                Ok(HttpResponse {
+                    status,
+                    headers: HeaderMap::new(),
+                    body,
+                })
+            })
+        }
+    }
+
+    fn chat(mock: &MockHttpClient) -> Chat {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.with_http_client(mock.clone())
+    }
+
+    fn chunk(content: &str) -> String {
+        serde_json::json!({
+            "id": "chunk",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{ "index": 0, "delta": { "content": content }, "logprobs": null, "finish_reason": null }],
+            "system_fingerprint": "fp",
+            "x_groq": { "id": "req" },
+        })
+        .to_string()
+    }
+
+    fn sse(chunks: &[String]) -> String {
+        chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect::<String>() + "data: [DONE]\n\n"
+    }
+
+    fn completion(content: &str) -> String {
+        serde_json::json!({
+            "id": "completion",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "logprobs": null, "finish_reason": "stop" }],
+            "usage": { "queue_time": 0.0, "prompt_tokens": 1, "prompt_time": 0.0, "completion_tokens": 1, "completion_time": 0.0, "total_tokens": 2, "total_time": 0.0 },
+            "system_fingerprint": "fp",
+            "x_groq": { "id": "req" },
+        })
+        .to_string()
+    }
+
+    async fn decode_lines(chunks: Vec<&'static [u8]>) -> Vec<Result<String, GroqError>> {
+        let body = futures::stream::iter(chunks.into_iter().map(|chunk| Ok::<_, io::Error>(Bytes::from_static(chunk))));
+        FramedRead::new(StreamReader::new(body), LineDecoder::default())
+            .map(|line| line.map_err(GroqError::from).and_then(|line| line))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn line_decoder_reassembles_multibyte_char_split_across_chunks() {
+        let text = "data: h\u{e9}llo\n".as_bytes();
+        let split = text.iter().position(|byte| *byte == 0xc3).unwrap() + 1;
+        let lines = decode_lines(vec![&text[..split], &text[split..]]).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].as_ref().unwrap(), "data: h\u{e9}llo");
+    }
+
+    #[tokio::test]
+    async fn line_decoder_reports_invalid_utf8_without_ending_the_stream() {
+        let lines = decode_lines(vec![b"bad \xff line\n", b"good line\n"]).await;
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(lines[0], Err(GroqError::InvalidUtf8(_))));
+        assert_eq!(lines[1].as_ref().unwrap(), "good line");
+    }
+
+    #[tokio::test]
+    async fn line_decoder_emits_unterminated_last_line_and_strips_crlf() {
+        let lines = decode_lines(vec![b"first\r\n", b"last"]).await;
+        let lines: Vec<String> = lines.into_iter().map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["first", "last"]);
+    }
+
+    #[tokio::test]
+    async fn stream_reassembles_content_split_inside_a_multibyte_char() {
+        let mock = MockHttpClient::default();
+        let body = sse(&[chunk("caf\u{e9}")]);
+        let split = body.find('\u{e9}').unwrap() + 1;
+        mock.respond(StatusCode::OK, vec![&body.as_bytes()[..split], &body.as_bytes()[split..]]);
+        let text = chat(&mock).stream_to_string().await.unwrap();
+        assert_eq!(text, "caf\u{e9}");
+    }
+
+    async fn parse_lines(lines: &[&str]) -> Vec<Result<ChatResponse, GroqError>> {
+        let lines: Vec<Result<String, GroqError>> = lines.iter().map(|line| Ok(line.to_string())).collect();
+        parse_sse_lines(futures::stream::iter(lines)).collect().await
+    }
+
+    #[tokio::test]
+    async fn sse_error_event_is_surfaced_as_err() {
+        let error = r#"{"error":{"message":"overloaded","type":"server_error","param":null,"code":null}}"#;
+        let first = format!("data: {}", chunk("a"));
+        let items = parse_lines(&[&first, "", "event: error", &format!("data: {}", error), ""]).await;
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        match &items[1] {
+            Err(GroqError::Api { error, .. }) => assert_eq!(error.to_string(), "Chat error: overloaded"),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn sse_comments_and_done_are_ignored() {
+        let data = format!("data: {}", chunk("a"));
+        let items = parse_lines(&[": keep-alive", &data, "", "data: [DONE]", ""]).await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap().content(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn sse_event_without_trailing_blank_line_is_flushed() {
+        let data = format!("data: {}", chunk("a"));
+        let items = parse_lines(&[&data]).await;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_ok());
+    }
+
+    #[tokio::test]
+    async fn two_data_payloads_in_one_frame_yield_two_chunks() {
+        let frame = format!("  data: {} data: {}  ", chunk("a"), chunk("b"));
+        let items = parse_lines(&[&frame, ""]).await;
+        let contents: Vec<String> = items.into_iter().map(|item| item.unwrap().content().unwrap().to_string()).collect();
+        assert_eq!(contents, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn concatenated_bare_json_objects_yield_separate_chunks() {
+        let frame = format!("{}{}", chunk("a"), chunk("b"));
+        let items = parse_lines(&[&frame]).await;
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn sampling_setters_reject_non_finite_values() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert!(chat.set_top_p(value).is_err());
+            assert!(chat.set_frequency_penalty(value).is_err());
+            assert!(chat.set_presence_penalty(value).is_err());
+            assert!(chat.set_temperature(value).is_err());
+        }
+        assert!(chat.set_top_p(0.5).is_ok());
+        assert!(chat.set_frequency_penalty(-1.5).is_ok());
+        assert!(chat.set_presence_penalty(1.5).is_ok());
+    }
+
+    #[test]
+    fn choice_without_delta_or_message_is_an_empty_assistant_message() {
+        let choice: ChatChoice = serde_json::from_str(r#"{"index":0,"logprobs":null,"finish_reason":"stop"}"#).unwrap();
+        assert_eq!(choice.message.role, ChatRole::Assistant);
+        assert_eq!(choice.content(), None);
+        assert_eq!(choice.finish_reason.as_deref(), Some("stop"));
+    }
+
+    #[test]
+    fn delta_without_role_defaults_to_assistant() {
+        let choice: ChatChoice = serde_json::from_str(r#"{"index":0,"delta":{"content":"hi"},"logprobs":null,"finish_reason":null}"#).unwrap();
+        assert_eq!(choice.message.role, ChatRole::Assistant);
+        assert_eq!(choice.content(), Some("hi"));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_fails_a_stalled_stream() {
+        let mock = MockHttpClient::default();
+        let first = format!("data: {}\n\n", chunk("a"));
+        mock.respond_then_stall(StatusCode::OK, vec![first.as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_idle_timeout(Duration::from_millis(50));
+        let items: Vec<_> = chat.stream().await.unwrap().collect().await;
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(matches!(items[1], Err(GroqError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_leaves_a_flowing_stream_alone() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_idle_timeout(Duration::from_millis(50));
+        assert_eq!(chat.stream_to_string().await.unwrap(), "ab");
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn overall_timeout_fails_a_request_without_response() {
+        // The listener accepts connections but never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.set_api_url(format!("http://{}/chat/completions", address));
+        chat.set_overall_timeout(Duration::from_millis(100));
+        let result = chat.send().await;
+        drop(listener);
+        assert!(matches!(result, Err(GroqError::Timeout)), "expected a timeout, got {:?}", result.err());
+    }
+
+    #[test]
+    fn request_json_round_trips_into_chat_request() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.set_temperature(0.3).unwrap();
+        let request: ChatRequest = serde_json::from_str(&chat.request_json().unwrap()).unwrap();
+        assert_eq!(&request, chat.get_request());
+        assert!(request.parallel_tool_calls());
+        assert!(request.tools().is_empty());
+    }
+
+    #[test]
+    fn minimal_saved_request_gets_the_constructor_defaults() {
+        let request: ChatRequest = serde_json::from_str(r#"{"model":"test-model","messages":[]}"#).unwrap();
+        assert_eq!(request, ChatRequest::new("test-model".to_string(), vec![]));
+        assert_eq!(request.temperature(), 1.0);
+        assert_eq!(request.top_p(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn saved_request_is_sent_unchanged_by_from_request() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("hi").as_bytes()]);
+        let saved = r#"{"model":"test-model","messages":[{"role":"user","content":"hello"}],"temperature":0.5,"seed":7}"#;
+        let request: ChatRequest = serde_json::from_str(saved).unwrap();
+        let chat = Chat::from_request("test-key".to_string(), request).with_http_client(mock.clone());
+        let response = chat.send().await.unwrap();
+        assert_eq!(response.content(), Some("hi"));
+        let sent: Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+        assert_eq!(sent["temperature"], 0.5);
+        assert_eq!(sent["seed"], 7);
+        assert_eq!(sent["model"], "test-model");
+    }
+
+    #[test]
+    fn extra_param_is_sent_at_the_top_level() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_extra_param("experimental".to_string(), serde_json::json!({ "on": true })).unwrap();
+        let body: Value = serde_json::from_str(&chat.request_json().unwrap()).unwrap();
+        assert_eq!(body["experimental"]["on"], true);
+        let saved = serde_json::to_string(chat.get_request()).unwrap();
+        let reloaded: ChatRequest = serde_json::from_str(&saved).unwrap();
+        assert_eq!(&reloaded, chat.get_request());
+    }
+
+    #[test]
+    fn extra_param_cannot_shadow_a_typed_parameter() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        assert!(chat.set_extra_param("temperature".to_string(), serde_json::json!(0.3)).is_err());
+        let saved = serde_json::to_string(chat.get_request()).unwrap();
+        assert_eq!(saved.matches("\"temperature\"").count(), 1);
+    }
+
+    #[test]
+    fn session_round_trips_through_json() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello   there", None));
+        chat.set_assistant_prefill("{");
+        chat.set_compact_whitespace(true);
+        chat.set_serialize_empty_tools(true);
+        chat.set_include_reasoning_in_history(false);
+        chat.set_max_message_tokens(100);
+        let saved = serde_json::to_string(&chat.export_session()).unwrap();
+
+        let mut restored = Chat::new("other-key".to_string(), "other-model".to_string());
+        restored.import_session(serde_json::from_str(&saved).unwrap());
+        assert_eq!(restored.request_json().unwrap(), chat.request_json().unwrap());
+        assert_eq!(restored.get_request(), chat.get_request());
+    }
+
+    fn tool_call_response(arguments: &str) -> String {
+        serde_json::json!({
+            "id": "completion",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "lookup", "arguments": arguments } }],
+                },
+                "logprobs": null,
+                "finish_reason": "tool_calls",
+            }],
+            "system_fingerprint": "fp",
+            "x_groq": { "id": "req" },
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn malformed_tool_arguments_are_returned_to_the_model() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_call_response("{not json").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("done").as_bytes()]);
+        let mut chat = chat(&mock);
+        let response = chat.run_with_tools(|_, _| async { Ok("unused".to_string()) }, 3).await.unwrap();
+        assert_eq!(response.content(), Some("done"));
+
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[1].tool_calls.is_some());
+        assert_eq!(messages[2].role, ChatRole::Tool);
+        assert!(messages[2].content.as_deref().unwrap().starts_with("Invalid JSON arguments for tool lookup"));
+    }
+
+    #[tokio::test]
+    async fn failing_dispatcher_leaves_no_dangling_tool_calls() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_call_response("{}").as_bytes()]);
+        let mut chat = chat(&mock);
+        let result = chat.run_with_tools(|_, _| async { Err(GroqError::Validation("tool failed".to_string())) }, 3).await;
+        assert!(matches!(result, Err(GroqError::Validation(_))));
+        assert_eq!(chat.number_of_chat_messages(), 1);
+    }
+
+    #[test]
+    fn unknown_service_tier_still_decodes() {
+        let mut response: Value = serde_json::from_str(&completion("hi")).unwrap();
+        response["service_tier"] = serde_json::json!("performance");
+        let response: ChatResponse = serde_json::from_value(response).unwrap();
+        assert_eq!(response.service_tier, Some(ChatServiceTier::Other("performance".to_string())));
+        assert_eq!(serde_json::to_value(ChatServiceTier::Other("performance".to_string())).unwrap(), "performance");
+        assert_eq!(serde_json::to_value(ChatServiceTier::OnDemand).unwrap(), "on_demand");
+    }
+
+    fn event_names(events: &[Result<StreamEvent, GroqError>]) -> Vec<&'static str> {
+        events.iter()
+            .map(|event| match event {
+                Ok(StreamEvent::Chunk(_)) => "chunk",
+                Ok(StreamEvent::Usage(_)) => "usage",
+                Ok(StreamEvent::Done) => "done",
+                Err(_) => "error",
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stream_events_end_with_usage_and_done() {
+        let mock = MockHttpClient::default();
+        let mut usage_chunk: Value = serde_json::from_str(&chunk("")).unwrap();
+        usage_chunk["choices"] = serde_json::json!([]);
+        usage_chunk["x_groq"]["usage"] = serde_json::from_str::<Value>(&completion(""))
+            .unwrap()["usage"].clone();
+        let body = sse(&[chunk("a"), chunk("b"), usage_chunk.to_string()]);
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let events: Vec<_> = chat(&mock).stream_events().await.unwrap().collect().await;
+        assert_eq!(event_names(&events), vec!["chunk", "chunk", "usage", "done"]);
+    }
+
+    #[tokio::test]
+    async fn stream_events_omit_done_after_an_error() {
+        let mock = MockHttpClient::default();
+        let body = format!("data: {}\n\ndata: {{broken\n\ndata: {}\n\n", chunk("a"), chunk("b"));
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let events: Vec<_> = chat(&mock).stream_events().await.unwrap().collect().await;
+        assert_eq!(event_names(&events), vec!["chunk", "error", "chunk"]);
+    }
+
+    #[tokio::test]
+    async fn request_hook_sees_the_body_sent_over_the_wire() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_assistant_prefill("{");
+        let seen = Arc::new(Mutex::new(vec![]));
+        let recorder = seen.clone();
+        chat.on_request(Box::new(move |request| recorder.lock().unwrap().push(request.clone())));
+        chat.stream_to_string().await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0]["stream"], true);
+        assert_eq!(seen[0]["messages"][1]["content"], "{");
+        assert!(seen[0].get("parallel_tool_calls").is_none());
+        assert_eq!(seen[0], serde_json::from_str::<Value>(&mock.requests()[0].body).unwrap());
+    }
+
+    #[tokio::test]
+    async fn regenerate_bumps_the_seed_only_when_asked() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("one").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("two").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_seed(42);
+        chat.add_chat_message(ChatMessage::new(ChatRole::Assistant, "zero", None));
+
+        chat.regenerate(false).await.unwrap();
+        assert_eq!(chat.get_seed(), Some(42));
+        chat.regenerate(true).await.unwrap();
+        assert_eq!(chat.get_seed(), Some(43));
+
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content.as_deref(), Some("two"));
+    }
+
+    #[tokio::test]
+    async fn server_error_with_json_body_is_an_api_error() {
+        let mock = MockHttpClient::default();
+        let body = r#"{"error":{"message":"over capacity","type":"server_error","param":null,"code":null}}"#;
+        mock.respond(StatusCode::SERVICE_UNAVAILABLE, vec![body.as_bytes()]);
+        let error = chat(&mock).send().await.unwrap_err();
+        assert!(matches!(error, GroqError::Api { status: Some(StatusCode::SERVICE_UNAVAILABLE), .. }));
+        assert!(error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn server_error_with_html_body_keeps_the_body() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::BAD_GATEWAY, vec![b"<html>Bad Gateway</html>"]);
+        match chat(&mock).send().await {
+            Err(GroqError::Status { status, body }) => {
+                assert_eq!(status, StatusCode::BAD_GATEWAY);
+                assert_eq!(body, "<html>Bad Gateway</html>");
+            }
+            other => panic!("expected a server error, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_error_carries_the_status() {
+        let mock = MockHttpClient::default();
+        let body = r#"{"error":{"message":"Invalid API Key","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#;
+        mock.respond(StatusCode::UNAUTHORIZED, vec![body.as_bytes()]);
+        let error = chat(&mock).send().await.unwrap_err();
+        assert!(matches!(error, GroqError::Api { status: Some(StatusCode::UNAUTHORIZED), .. }));
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn send_json_or_falls_back_on_invalid_json() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion(r#"{"answer": 42}"#).as_bytes()])
+            .respond(StatusCode::OK, vec![completion("not json").as_bytes()]);
+        let chat = chat(&mock);
+        let parsed: Value = chat.send_json_or(|| Value::Null).await.unwrap();
+        assert_eq!(parsed["answer"], 42);
+        let fallback: Value = chat.send_json_or(|| Value::Null).await.unwrap();
+        assert_eq!(fallback, Value::Null);
+    }
+
+    #[tokio::test]
+    async fn stream_lenient_skips_a_garbage_chunk() {
+        let mock = MockHttpClient::default();
+        let body = format!("data: {}\n\ndata: {{garbage\n\ndata: {}\n\ndata: [DONE]\n\n", chunk("a"), chunk("b"));
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let items: Vec<_> = chat(&mock).stream_lenient().await.unwrap().collect().await;
+        let contents: Vec<String> = items.into_iter().map(|item| item.unwrap().content().unwrap().to_string()).collect();
+        assert_eq!(contents, vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn stream_strict_stops_at_a_malformed_chunk() {
+        let mock = MockHttpClient::default();
+        let body = format!("data: {}\n\ndata: {{broken\n\ndata: {}\n\ndata: [DONE]\n\n", chunk("a"), chunk("b"));
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let items: Vec<_> = chat(&mock).stream_strict().await.unwrap().collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().content(), Some("a"));
+        assert!(matches!(items[1], Err(GroqError::Decode { .. })));
+    }
+
+    #[tokio::test]
+    async fn regenerate_replaces_the_last_reply() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("fresh").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.add_chat_message(ChatMessage::new(ChatRole::Assistant, "stale", None));
+        let response = chat.regenerate(false).await.unwrap();
+        assert_eq!(response.content(), Some("fresh"));
+
+        let sent: Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+        assert_eq!(sent["messages"].as_array().unwrap().len(), 1);
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content.as_deref(), Some("fresh"));
+    }
+
+    #[tokio::test]
+    async fn failed_regenerate_restores_the_reply_and_seed() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::SERVICE_UNAVAILABLE, vec![b"unavailable"]);
+        let mut chat = chat(&mock);
+        chat.set_seed(42);
+        chat.add_chat_message(ChatMessage::new(ChatRole::Assistant, "kept", None));
+        assert!(chat.regenerate(true).await.is_err());
+        assert_eq!(chat.get_seed(), Some(42));
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content.as_deref(), Some("kept"));
+    }
+
+    #[tokio::test]
+    async fn client_error_with_html_body_keeps_the_status() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::FORBIDDEN, vec![b"<html>Forbidden</html>"]);
+        let error = chat(&mock).send().await.unwrap_err();
+        match &error {
+            GroqError::Status { status, body } => {
+                assert_eq!(*status, StatusCode::FORBIDDEN);
+                assert_eq!(body, "<html>Forbidden</html>");
+            }
+            other => panic!("expected a status error, got {:?}", other),
+        }
+        assert!(!error.is_transient());
+    }
+
+    #[tokio::test]
+    async fn every_error_response_reports_its_status() {
+        let mock = MockHttpClient::default();
+        let body = r#"{"error":{"message":"Invalid API Key","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#;
+        mock.respond(StatusCode::UNAUTHORIZED, vec![body.as_bytes()])
+            .respond(StatusCode::UNAUTHORIZED, vec![b"Unauthorized"]);
+        let chat = chat(&mock);
+        assert_eq!(chat.send().await.unwrap_err().status(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(chat.send().await.unwrap_err().status(), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn validate_lists_a_missing_tool_choice_function() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_tool_choice(ToolChoice::function("missing"));
+        let problems = chat.validate().unwrap_err();
+        assert!(problems.contains(&"The request has no messages".to_string()));
+        assert!(problems.iter().any(|problem| problem.contains("'missing'")));
+    }
+
+    #[tokio::test]
+    async fn full_validation_before_send_is_opt_in() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("{}").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_response_format(ChatResponseFormat::JsonObject);
+        chat.send().await.unwrap();
+
+        chat.set_validate_before_send(true);
+        assert!(matches!(chat.send().await, Err(GroqError::Validation(_))));
+        chat.set_assistant_prefill("Here is the JSON:");
+        assert!(chat.validate().is_ok());
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    fn chat_with_unsupported_tier(mock: &MockHttpClient) -> Chat {
+        let mut capability_table = CapabilityTable::new();
+        capability_table.insert("test-model", ModelCapabilities {
+            context_window: None,
+            service_tiers: Some(vec![ChatServiceTier::OnDemand]),
+        });
+        let mut chat = chat(mock);
+        chat.set_capability_table(capability_table);
+        chat.set_service_tier(ChatServiceTier::Flex);
+        chat
+    }
+
+    #[tokio::test]
+    async fn enforced_service_tier_rejects_an_unsupported_tier() {
+        let mock = MockHttpClient::default();
+        let mut chat = chat_with_unsupported_tier(&mock);
+        assert_eq!(chat.check_service_tier().unwrap_err(), "Service tier flex is not supported by model test-model");
+        chat.set_enforce_service_tier(true);
+        assert!(matches!(chat.send().await, Err(GroqError::Validation(_))));
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn coerce_service_tier_clears_an_unsupported_tier() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("hi").as_bytes()]);
+        let mut chat = chat_with_unsupported_tier(&mock);
+        assert!(chat.coerce_service_tier());
+        assert_eq!(chat.get_service_tier(), None);
+        assert!(!chat.coerce_service_tier());
+        chat.send().await.unwrap();
+        let sent: Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+        assert!(sent.get("service_tier").is_none());
+    }
+
+    fn response_with_content(content: &str) -> ChatResponse {
+        serde_json::from_str(&completion(content)).unwrap()
+    }
+
+    #[test]
+    fn lenient_json_parsing_repairs_fences_and_trailing_commas() {
+        for content in [
+            "```json\n{\"a\": 1, \"b\": [1, 2,],}\n```",
+            "```\n{\"a\": 1, \"b\": [1, 2]}\n```",
+            "```{\n  \"a\": 1,\n  \"b\": [1, 2],\n}\n```",
+            "{\"a\": 1, \"b\": [1, 2],}",
+        ] {
+            let parsed: Value = response_with_content(content).parse_json_content_lenient().unwrap();
+            assert_eq!(parsed, serde_json::json!({ "a": 1, "b": [1, 2] }), "failed to repair {:?}", content);
+        }
+        let parsed: Value = response_with_content(r#"{"text": "a,}"}"#).parse_json_content_lenient().unwrap();
+        assert_eq!(parsed["text"], "a,}");
+        assert!(response_with_content("not json").parse_json_content_lenient::<Value>().is_err());
+        assert!(response_with_content("```json\n{\"a\": 1,}\n```").parse_json_content::<Value>().is_err());
+    }
+
+    #[tokio::test]
+    async fn first_token_timeout_fails_a_stream_without_content() {
+        let mock = MockHttpClient::default();
+        let empty = format!("data: {}\n\n", chunk(""));
+        mock.respond_then_stall(StatusCode::OK, vec![empty.as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_first_token_timeout(Duration::from_millis(50));
+        let items: Vec<_> = chat.stream().await.unwrap().collect().await;
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(matches!(items[1], Err(GroqError::FirstTokenTimeout(timeout)) if timeout == Duration::from_millis(50)));
+    }
+
+    #[tokio::test]
+    async fn first_token_timeout_stops_once_content_arrives() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_first_token_timeout(Duration::from_millis(50));
+        assert_eq!(chat.stream_to_string().await.unwrap(), "ab");
+    }
+
+    fn quick_retries(chat: &mut Chat) {
+        chat.set_retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+        });
+    }
+
+    #[tokio::test]
+    async fn stream_with_retry_retries_failures_before_content() {
+        let mock = MockHttpClient::default();
+        let empty = format!("data: {}\n\n", chunk(""));
+        mock.respond(StatusCode::SERVICE_UNAVAILABLE, vec![b"unavailable"])
+            .respond_then_stall(StatusCode::OK, vec![empty.as_bytes()])
+            .respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        quick_retries(&mut chat);
+        chat.set_idle_timeout(Duration::from_millis(50));
+        let items: Vec<_> = chat.stream_with_retry().await.unwrap().collect().await;
+        let contents: Vec<String> = items.into_iter().map(|item| item.unwrap().content().unwrap().to_string()).collect();
+        assert_eq!(contents, vec!["a", "b"]);
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn stream_with_retry_passes_errors_after_content_through() {
+        let mock = MockHttpClient::default();
+        let first = format!("data: {}\n\n", chunk("a"));
+        mock.respond_then_stall(StatusCode::OK, vec![first.as_bytes()]);
+        let mut chat = chat(&mock);
+        quick_retries(&mut chat);
+        chat.set_idle_timeout(Duration::from_millis(50));
+        let items: Vec<_> = chat.stream_with_retry().await.unwrap().collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().content(), Some("a"));
+        assert!(matches!(items[1], Err(GroqError::Timeout)));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_resumable_stream_reports_the_partial_text() {
+        let mock = MockHttpClient::default();
+        let body = format!("data: {}\n\ndata: {}\n\n", chunk("Hello, "), chunk("wor"));
+        mock.respond_then_stall(StatusCode::OK, vec![body.as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_idle_timeout(Duration::from_millis(50));
+        let mut stream = chat.stream_resumable().await.unwrap();
+        let mut error = None;
+        while let Some(item) = stream.next().await {
+            if let Err(e) = item {
+                error = Some(e);
+            }
+        }
+        assert_eq!(stream.accumulated(), "Hello, wor");
+        match error {
+            Some(GroqError::StreamInterrupted { partial, source }) => {
+                assert_eq!(partial, "Hello, wor");
+                assert!(matches!(*source, GroqError::Timeout));
+            }
+            other => panic!("expected an interrupted stream, got {:?}", other),
+        }
+
+        chat.resume_from_partial(stream.accumulated());
+        let last = chat.get_chat_messages().pop().unwrap();
+        assert_eq!(last.role, ChatRole::Assistant);
+        assert_eq!(last.content.as_deref(), Some("Hello, wor"));
+    }
+
+    #[tokio::test]
+    async fn send_cached_serves_repeated_requests_from_the_cache() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("first").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("other endpoint").as_bytes()]);
+        let chat = chat(&mock);
+        let first = chat.send_cached().await.unwrap();
+        assert!(!first.from_cache);
+        let second = chat.send_cached().await.unwrap();
+        assert!(second.from_cache);
+        assert_eq!(second.response.content(), Some("first"));
+        assert_eq!(mock.requests().len(), 1);
+
+        let mut elsewhere = chat.clone();
+        elsewhere.set_api_url("https://example.test/v1/chat/completions".to_string());
+        let third = elsewhere.send_cached().await.unwrap();
+        assert!(!third.from_cache);
+        assert_eq!(third.response.content(), Some("other endpoint"));
+    }
+
+    fn hash_of(request: &ChatRequest) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        request.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn identical_requests_are_equal_and_hash_alike() {
+        let build = || {
+            let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+            chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+            chat.set_temperature(0.7).unwrap();
+            chat.set_extra_param("b".to_string(), serde_json::json!(2)).unwrap();
+            chat.set_extra_param("a".to_string(), serde_json::json!(1)).unwrap();
+            chat.get_request().clone()
+        };
+        assert_eq!(build(), build());
+        assert_eq!(hash_of(&build()), hash_of(&build()));
+
+        let mut other = Chat::from_request("test-key".to_string(), build());
+        other.set_temperature(0.8).unwrap();
+        assert_ne!(other.get_request(), &build());
+        assert_ne!(hash_of(other.get_request()), hash_of(&build()));
+    }
+
+    #[tokio::test]
+    async fn run_with_tools_gives_up_at_the_iteration_cap() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_call_response("{}").as_bytes()])
+            .respond(StatusCode::OK, vec![tool_call_response("{}").as_bytes()]);
+        let mut chat = chat(&mock);
+        let result = chat.run_with_tools(|_, _| async { Ok("again".to_string()) }, 2).await;
+        match result {
+            Err(GroqError::MaxToolIterationsExceeded { max_iterations, messages }) => {
+                assert_eq!(max_iterations, 2);
+                assert_eq!(messages.len(), 5);
+                assert_eq!(messages[4].content.as_deref(), Some("again"));
+            }
+            other => panic!("expected the iteration cap, got {:?}", other),
+        }
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    fn tool_calls_response(names: &[&str]) -> String {
+        let tool_calls: Vec<Value> = names.iter().enumerate()
+            .map(|(i, name)| serde_json::json!({
+                "id": format!("call_{}", i + 1),
+                "type": "function",
+                "function": { "name": name, "arguments": "{}" },
+            }))
+            .collect();
+        let mut response: Value = serde_json::from_str(&tool_call_response("{}")).unwrap();
+        response["choices"][0]["message"]["tool_calls"] = Value::Array(tool_calls);
+        response.to_string()
+    }
+
+    #[tokio::test]
+    async fn tool_results_keep_call_order_when_the_second_finishes_first() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_calls_response(&["slow", "fast"]).as_bytes()])
+            .respond(StatusCode::OK, vec![completion("done").as_bytes()]);
+        let mut chat = chat(&mock);
+        let finished = Arc::new(Mutex::new(vec![]));
+        let dispatcher = |name: String, _| {
+            let finished = finished.clone();
+            async move {
+                if name == "slow" {
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                }
+                finished.lock().unwrap().push(name.clone());
+                Ok(format!("{} result", name))
+            }
+        };
+        chat.run_with_tools(dispatcher, 3).await.unwrap();
+        assert_eq!(*finished.lock().unwrap(), vec!["fast", "slow"]);
+
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(messages[2].content.as_deref(), Some("slow result"));
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("call_2"));
+        assert_eq!(messages[3].content.as_deref(), Some("fast result"));
+    }
+
+    #[test]
+    fn add_tool_results_sorts_by_call_order() {
+        let response: ChatResponse = serde_json::from_str(&tool_calls_response(&["a", "b"])).unwrap();
+        let tool_calls: Vec<ToolCall> = response.all_tool_calls().into_iter().cloned().collect();
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_tool_results(&tool_calls, vec![
+            ("unknown".to_string(), "x".to_string()),
+            ("call_2".to_string(), "b".to_string()),
+            ("call_1".to_string(), "a".to_string()),
+        ]);
+        let ids: Vec<Option<String>> = chat.get_chat_messages().into_iter().map(|message| message.tool_call_id).collect();
+        assert_eq!(ids, vec![Some("call_1".to_string()), Some("call_2".to_string()), Some("unknown".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn key_provider_is_asked_for_every_request() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("one").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("two").as_bytes()]);
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let chat = chat(&mock).with_key_provider(Box::new(move || {
+            let mut calls = counter.lock().unwrap();
+            *calls += 1;
+            format!("key-{}", calls)
+        }));
+        chat.send().await.unwrap();
+        chat.send().await.unwrap();
+        let authorizations: Vec<String> = mock.requests().iter()
+            .map(|request| request.headers[AUTHORIZATION].to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(authorizations, vec!["Bearer key-1", "Bearer key-2"]);
+    }
+
+    #[tokio::test]
+    async fn stream_chunked_merges_tiny_deltas() {
+        let mock = MockHttpClient::default();
+        let deltas: Vec<String> = "abcdefg".chars().map(|c| chunk(&c.to_string())).collect();
+        mock.respond(StatusCode::OK, vec![sse(&deltas).as_bytes()]);
+        let items: Vec<_> = chat(&mock).stream_chunked(3).await.unwrap().collect().await;
+        let contents: Vec<String> = items.into_iter().map(|item| item.unwrap().content().unwrap().to_string()).collect();
+        assert_eq!(contents, vec!["abc", "def", "g"]);
+    }
+
+    #[tokio::test]
+    async fn stream_chunked_flushes_the_buffer_before_an_error() {
+        let mock = MockHttpClient::default();
+        let body = format!("data: {}\n\ndata: {}\n\ndata: {{broken\n\n", chunk("a"), chunk("b"));
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let items: Vec<_> = chat(&mock).stream_chunked(10).await.unwrap().collect().await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap().content(), Some("ab"));
+        assert!(matches!(items[1], Err(GroqError::Decode { .. })));
+    }
+
+    #[tokio::test]
+    async fn stream_over_the_chunk_limit_ends_with_an_error() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b"), chunk("c")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_max_stream_chunks(2);
+        let items: Vec<_> = chat.stream().await.unwrap().collect().await;
+        assert_eq!(items.len(), 3);
+        assert!(items[..2].iter().all(Result::is_ok));
+        assert!(matches!(items[2], Err(GroqError::StreamChunkLimit(2))));
+    }
+
+    #[tokio::test]
+    async fn moderator_blocks_a_banned_phrase_before_sending() {
+        let mock = MockHttpClient::default();
+        let mut chat = chat(&mock);
+        chat.set_content_moderator(|messages: &[ChatMessage]| {
+            match messages.iter().any(|message| message.content.as_deref().is_some_and(|content| content.contains("forbidden"))) {
+                true => Err("banned phrase".to_string()),
+                false => Ok(()),
+            }
+        });
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "say the forbidden word", None));
+        assert!(matches!(chat.send().await, Err(GroqError::Moderation(reason)) if reason == "banned phrase"));
+        assert!(matches!(chat.stream().await.err(), Some(GroqError::Moderation(_))));
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn session_usage_sums_every_send() {
+        let mock = MockHttpClient::default();
+        let mut response: Value = serde_json::from_str(&completion("hi")).unwrap();
+        response["usage"]["total_time"] = serde_json::json!(0.25);
+        let response = response.to_string();
+        mock.respond(StatusCode::OK, vec![response.as_bytes()])
+            .respond(StatusCode::OK, vec![response.as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.send().await.unwrap();
+        chat.send().await.unwrap();
+        let usage = chat.session_usage();
+        assert_eq!(usage.prompt_tokens, 2);
+        assert_eq!(usage.completion_tokens, 2);
+        assert_eq!(usage.total_tokens, 4);
+        assert_eq!(usage.total_time, 0.5);
+
+        chat.reset_session_usage();
+        assert_eq!(chat.session_usage().total_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn stream_to_writer_writes_the_full_reply() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("Hello"), chunk(", "), chunk("world")]).as_bytes()]);
+        let mut output = Vec::new();
+        let response = chat(&mock).stream_to_writer(&mut output).await.unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Hello, world");
+        assert_eq!(response.content(), Some("Hello, world"));
+    }
+
+    #[tokio::test]
+    async fn forcing_a_missing_tool_fails_before_sending() {
+        let mock = MockHttpClient::default();
+        let mut chat = chat(&mock);
+        chat.add_tool(Tool {
+            function: Function { description: None, name: Some("lookup".to_string()), parameters: None },
+            tool_type: ToolType::Function,
+        });
+        chat.set_tool_choice(ToolChoice::function("missing"));
+        match chat.send().await {
+            Err(GroqError::Validation(message)) => assert!(message.contains("'missing'"), "{}", message),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        assert!(mock.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_decode_keeps_the_raw_body() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![br#"{"unexpected":true}"#]);
+        match chat(&mock).send().await {
+            Err(GroqError::Decode { body, .. }) => assert_eq!(body, r#"{"unexpected":true}"#),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inter_token_stats_from_gaps() {
+        let gaps: Vec<Duration> = (1..=20).rev().map(Duration::from_millis).collect();
+        let stats = InterTokenStats::from_gaps(&gaps).unwrap();
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(20));
+        assert_eq!(stats.mean, Duration::from_micros(10_500));
+        assert_eq!(stats.p95, Duration::from_millis(19));
+        assert_eq!(InterTokenStats::from_gaps(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn stream_timed_measures_gaps_between_content_deltas() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk(""), chunk("b"), chunk("c")]).as_bytes()]);
+        let timed = chat(&mock).stream_timed().await.unwrap();
+        assert_eq!(timed.response.content(), Some("abc"));
+        let stats = timed.inter_token.unwrap();
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+        assert!(stats.p95 <= stats.max);
+    }
+
+    #[test]
+    fn empty_tools_are_omitted_unless_asked_for() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        assert!(chat.request_value().unwrap().get("tools").is_none());
+        chat.set_serialize_empty_tools(true);
+        assert_eq!(chat.request_value().unwrap()["tools"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn custom_headers_are_sent_and_invalid_ones_rejected() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("hi").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_header("x-routing-key".to_string(), "eu-1".to_string()).unwrap();
+        assert!(chat.set_header("bad header".to_string(), "value".to_string()).is_err());
+        assert!(chat.set_header("x-trace".to_string(), "line\nbreak".to_string()).is_err());
+        chat.send().await.unwrap();
+        let headers = &mock.requests()[0].headers;
+        assert_eq!(headers["x-routing-key"], "eu-1");
+        assert_eq!(headers[AUTHORIZATION], "Bearer test-key");
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+    }
+
+    /// Answers with `content` after `delay`, counting the requests in flight.
+    struct SlowHttpClient {
+        delay: Duration,
+        content: String,
+        in_flight: Arc<Mutex<(usize, usize)>>,
+    }
+
+    impl HttpClient for SlowHttpClient {
+        fn post(&self, _request: HttpRequest) -> crate::http::HttpFuture<'_> {
+            Box::pin(async move {
+                {
+                    let mut in_flight = self.in_flight.lock().unwrap();
+                    in_flight.0 += 1;
+                    in_flight.1 = in_flight.1.max(in_flight.0);
+                }
+                tokio::time::sleep(self.delay).await;
+                self.in_flight.lock().unwrap().0 -= 1;
+                Ok(HttpResponse::from_bytes(StatusCode::OK, completion(&self.content)))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_keeps_input_order_and_bounds_concurrency() {
+        let in_flight = Arc::new(Mutex::new((0, 0)));
+        let chats: Vec<Chat> = [40, 10, 30, 0].iter().enumerate()
+            .map(|(i, delay)| {
+                let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+                chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+                chat.with_http_client(SlowHttpClient {
+                    delay: Duration::from_millis(*delay),
+                    content: format!("reply {}", i),
+                    in_flight: in_flight.clone(),
+                })
+            })
+            .collect();
+        let results = Chat::send_batch(chats, 2).await;
+        let contents: Vec<String> = results.into_iter().map(|result| result.unwrap().content().unwrap().to_string()).collect();
+        assert_eq!(contents, vec!["reply 0", "reply 1", "reply 2", "reply 3"]);
+        assert_eq!(in_flight.lock().unwrap().1, 2);
+    }
+
+    #[tokio::test]
+    async fn sse_frames_parse_back_into_the_same_chunks() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b")]).as_bytes()]);
+        let frames: Vec<String> = sse_frames(chat(&mock).stream().await.unwrap())
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2], "data: [DONE]\n\n");
+        assert!(frames.iter().all(|frame| frame.starts_with("data: ") && frame.ends_with("\n\n")));
+
+        let text = frames.concat();
+        let lines: Vec<&str> = text.lines().collect();
+        let chunks: Vec<ChatResponse> = parse_lines(&lines).await.into_iter().map(Result::unwrap).collect();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(ChatResponse::is_chunk));
+        assert_eq!(chunks[0].content(), Some("a"));
+        assert_eq!(chunks[1].content(), Some("b"));
+        let frame: Value = serde_json::from_str(frames[0].trim().strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(frame["choices"][0]["delta"]["content"], "a");
+    }
+
+    #[tokio::test]
+    async fn audit_record_holds_the_exact_bytes_exchanged() {
+        let mock = MockHttpClient::default();
+        let reply = completion("hi");
+        mock.respond(StatusCode::OK, vec![reply.as_bytes()]);
+        let chat = chat(&mock);
+        let record = chat.send_audited().await.unwrap();
+        assert_eq!(record.request_bytes, mock.requests()[0].body.as_bytes());
+        assert_eq!(record.request_bytes, chat.request_json().unwrap().as_bytes());
+        assert_eq!(record.response_bytes, reply.as_bytes());
+        assert_eq!(record.status, 200);
+        assert_eq!(record.response().unwrap().content(), Some("hi"));
+    }
+
+    fn lookup_tool() -> Tool {
+        Tool {
+            function: Function {
+                description: Some("Looks something up".to_string()),
+                name: Some("lookup".to_string()),
+                parameters: Some(ParametersBuilder::new().add_string_param("query", "What to look up", true).build()),
+            },
+            tool_type: ToolType::Function,
+        }
+    }
+
+    #[test]
+    fn parallel_tool_calls_is_only_sent_with_tools() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_parallel_tool_calls(false);
+        assert!(!chat.get_parallel_tool_calls());
+        assert!(chat.request_value().unwrap().get("parallel_tool_calls").is_none());
+        chat.add_tool(lookup_tool());
+        assert_eq!(chat.request_value().unwrap()["parallel_tool_calls"], false);
+    }
+
+    #[test]
+    fn compact_whitespace_leaves_code_fences_alone() {
+        let text = "Fix   this:\n\n\n  please\t \n```rust\nfn main() {\n    let  x = 1;\n}\n```\n\n  thanks  ";
+        assert_eq!(
+            compact_whitespace(text),
+            "Fix this:\nplease\n```rust\nfn main() {\n    let  x = 1;\n}\n```\nthanks ",
+        );
+        assert_eq!(compact_whitespace("a  ```b  c"), "a ```b  c");
+    }
+
+    #[test]
+    fn compact_whitespace_applies_to_the_sent_messages() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "a   b\n\n```\nx   y\n```", None));
+        chat.set_compact_whitespace(true);
+        assert_eq!(chat.request_value().unwrap()["messages"][0]["content"], "a b\n```\nx   y\n```");
+        assert_eq!(chat.get_chat_messages()[0].content.as_deref(), Some("a   b\n\n```\nx   y\n```"));
+    }
+
+    #[test]
+    fn rate_limit_headers_are_parsed() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-ratelimit-limit-requests", "14400"),
+            ("x-ratelimit-limit-tokens", "18000"),
+            ("x-ratelimit-remaining-requests", "14370"),
+            ("x-ratelimit-remaining-tokens", "17997"),
+            ("x-ratelimit-reset-requests", "2m59.56s"),
+            ("x-ratelimit-reset-tokens", "7.66s"),
+            ("retry-after", "2"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        assert_eq!(RateLimitInfo::from_headers(&headers), RateLimitInfo {
+            limit_requests: Some(14400),
+            limit_tokens: Some(18000),
+            remaining_requests: Some(14370),
+            remaining_tokens: Some(17997),
+            reset_requests: Some(Duration::from_millis(179_560)),
+            reset_tokens: Some(Duration::from_millis(7_660)),
+            retry_after: Some(Duration::from_secs(2)),
+        });
+        assert_eq!(RateLimitInfo::from_headers(&HeaderMap::new()), RateLimitInfo::default());
+    }
+
+    #[test]
+    fn reset_durations_accept_every_unit() {
+        assert_eq!(parse_reset_duration("120ms"), Some(Duration::from_millis(120)));
+        assert_eq!(parse_reset_duration("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_reset_duration("0.5"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_reset_duration("soon"), None);
+        assert_eq!(parse_reset_duration("3d"), None);
+    }
+
+    #[test]
+    fn token_budget_trim_keeps_system_messages_and_drops_orphaned_tool_results() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            r#type: ToolType::Function,
+            function: ToolCallFunction { name: "lookup".to_string(), arguments: "{}".to_string() },
+        };
+        chat.set_chat_messages(vec![
+            ChatMessage::new(ChatRole::System, "sys", None),
+            ChatMessage::new(ChatRole::User, &"old question ".repeat(4), None),
+            ChatMessage { tool_calls: Some(vec![tool_call]), ..ChatMessage::new(ChatRole::Assistant, "", None) },
+            ChatMessage::new(ChatRole::Tool, &"lookup result ".repeat(4), Some("call_1".to_string())),
+            ChatMessage::new(ChatRole::User, "new", None),
+        ]);
+        chat.trim_to_token_budget(5);
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, ChatRole::System);
+        assert_eq!(messages[1].content.as_deref(), Some("new"));
+        assert!(chat.estimated_prompt_tokens() <= 5);
+
+        chat.trim_to_token_budget(0);
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, ChatRole::System);
+    }
+
+    #[test]
+    fn object_type_round_trips_known_and_unknown_values() {
+        for (raw, object) in [
+            ("chat.completion", ObjectType::ChatCompletion),
+            ("chat.completion.chunk", ObjectType::ChatCompletionChunk),
+            ("chat.completion.delta", ObjectType::Other("chat.completion.delta".to_string())),
+        ] {
+            let json = format!("\"{}\"", raw);
+            assert_eq!(serde_json::from_str::<ObjectType>(&json).unwrap(), object);
+            assert_eq!(serde_json::to_string(&object).unwrap(), json);
+            assert_eq!(object.to_string(), raw);
+        }
+        assert!(!response_with_content("hi").is_chunk());
+    }
+
+    #[test]
+    fn base_url_comes_from_the_environment() {
+        // The only test touching GROQ_BASE_URL; the mocks ignore the URL.
+        env::set_var("GROQ_BASE_URL", "http://localhost:8080/v1/");
+        let chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        env::remove_var("GROQ_BASE_URL");
+        assert_eq!(chat.get_base_url(), "http://localhost:8080/v1");
+        assert_eq!(chat.chat_url(), "http://localhost:8080/v1/chat/completions");
+        assert_eq!(base_url_from_env(), DEFAULT_BASE_URL);
+    }
+
+    #[test]
+    fn chat_url_follows_the_base_url_unless_overridden() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_base_url("https://example.com/openai/v1/".to_string());
+        assert_eq!(chat.chat_url(), "https://example.com/openai/v1/chat/completions");
+        chat.set_api_url("https://example.com/custom".to_string());
+        assert_eq!(chat.chat_url(), "https://example.com/custom");
+    }
+
+    #[test]
+    fn assistant_reasoning_is_only_sent_when_included() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        let mut user = ChatMessage::new(ChatRole::User, "why?", None);
+        user.reasoning = Some("not an assistant".to_string());
+        let mut assistant = ChatMessage::new(ChatRole::Assistant, "because", None);
+        assistant.reasoning = Some("thought about it".to_string());
+        chat.set_chat_messages(vec![user, assistant]);
+        assert_eq!(chat.request_value().unwrap()["messages"][1]["reasoning"], "thought about it");
+
+        chat.set_include_reasoning_in_history(false);
+        let request = chat.request_value().unwrap();
+        assert!(request["messages"][1].get("reasoning").is_none());
+        assert_eq!(request["messages"][1]["content"], "because");
+        assert_eq!(request["messages"][0]["reasoning"], "not an assistant");
+        assert!(chat.get_chat_messages()[1].reasoning.is_some());
+    }
+
+    #[tokio::test]
+    async fn assistant_prefill_is_the_last_message_sent() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("\"a\": 1}").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_assistant_prefill("{");
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "as JSON please", None));
+        let response = chat.send().await.unwrap();
+        assert_eq!(response.content(), Some("\"a\": 1}"));
+
+        let sent: Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+        let messages = sent["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"], "as JSON please");
+        assert_eq!(messages[2], serde_json::json!({ "role": "assistant", "content": "{" }));
+        assert_eq!(chat.number_of_chat_messages(), 2);
+
+        chat.clear_assistant_prefill();
+        assert_eq!(chat.request_value().unwrap()["messages"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn parameters_builder_produces_an_object_schema() {
+        let parameters = ParametersBuilder::new()
+            .add_string_param("city", "The city", true)
+            .add_number_param("radius", "Radius in km", false)
+            .add_integer_param("limit", "Maximum results", false)
+            .add_boolean_param("open_now", "Only open places", false)
+            .add_enum_param("unit", "Temperature unit", &["celsius", "fahrenheit"], true)
+            .add_param("tags", serde_json::json!({ "type": "array", "items": { "type": "string" } }), false)
+            .add_integer_param("limit", "Maximum results", true)
+            .build();
+        assert_eq!(parameters, serde_json::json!({
+            "type": "object",
+            "properties": {
+                "city": { "type": "string", "description": "The city" },
+                "radius": { "type": "number", "description": "Radius in km" },
+                "limit": { "type": "integer", "description": "Maximum results" },
+                "open_now": { "type": "boolean", "description": "Only open places" },
+                "unit": { "type": "string", "description": "Temperature unit", "enum": ["celsius", "fahrenheit"] },
+                "tags": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["city", "unit", "limit"],
+        }));
+        assert_eq!(ParametersBuilder::new().build(), serde_json::json!({ "type": "object", "properties": {}, "required": [] }));
+    }
+
+    fn choice_chunk(index: u64, content: &str, finish_reason: Option<&str>) -> String {
+        let mut chunk: Value = serde_json::from_str(&chunk(content)).unwrap();
+        chunk["choices"][0]["index"] = index.into();
+        chunk["choices"][0]["finish_reason"] = finish_reason.into();
+        chunk.to_string()
+    }
+
+    #[tokio::test]
+    async fn interleaved_choices_are_accumulated_per_index() {
+        let mock = MockHttpClient::default();
+        let chunks = [
+            choice_chunk(0, "a", None),
+            choice_chunk(1, "x", None),
+            choice_chunk(1, "y", None),
+            choice_chunk(0, "b", Some("stop")),
+            choice_chunk(1, "z", Some("length")),
+        ];
+        mock.respond(StatusCode::OK, vec![sse(&chunks).as_bytes()]);
+        let deltas: Vec<ChoiceDelta> = chat(&mock).stream_by_choice().await.unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let summary: Vec<(u64, &str, &str, Option<&str>)> = deltas.iter()
+            .map(|delta| (delta.index, delta.content.as_str(), delta.accumulated.as_str(), delta.finish_reason.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            (0, "a", "a", None),
+            (1, "x", "x", None),
+            (1, "y", "xy", None),
+            (0, "b", "ab", Some("stop")),
+            (1, "z", "xyz", Some("length")),
+        ]);
+    }
+
+    #[test]
+    fn think_blocks_are_split_from_the_answer() {
+        let response = response_with_content("<think>\nFirst idea.\n</think>\nThe answer.<think>Second idea.</think> Done.");
+        assert_eq!(response.content_without_thinking().as_deref(), Some("The answer. Done."));
+        assert_eq!(response.thinking().as_deref(), Some("First idea.\nSecond idea."));
+
+        let unclosed = response_with_content("Partial answer <think>still thinking");
+        assert_eq!(unclosed.content_without_thinking().as_deref(), Some("Partial answer"));
+        assert_eq!(unclosed.thinking().as_deref(), Some("still thinking"));
+
+        let plain = response_with_content("No reasoning here.");
+        assert_eq!(plain.content_without_thinking().as_deref(), Some("No reasoning here."));
+        assert_eq!(plain.thinking(), None);
+    }
+
+    #[test]
+    fn request_size_matches_the_serialized_body() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "héllo wörld", None));
+        let json = chat.request_json().unwrap();
+        assert_eq!(chat.request_size_bytes().unwrap(), json.len());
+        assert!(json.len() > json.chars().count());
+    }
+
+    #[test]
+    fn set_tools_replaces_the_tool_list() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_tool(lookup_tool());
+        let mut other = lookup_tool();
+        other.function.name = Some("search".to_string());
+        chat.set_tools(vec![other.clone(), other]);
+        let names: Vec<Option<String>> = chat.get_tools().into_iter().map(|tool| tool.function.name).collect();
+        assert_eq!(names, vec![Some("search".to_string()), Some("search".to_string())]);
+        assert_eq!(chat.request_value().unwrap()["tools"].as_array().unwrap().len(), 2);
+        chat.set_tools(vec![]);
+        assert!(chat.get_tools().is_empty());
+        assert!(chat.request_value().unwrap().get("tools").is_none());
+    }
+
+    #[tokio::test]
+    async fn user_is_sent_with_the_request() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("hi").as_bytes()]);
+        let mut chat = chat(&mock);
+        assert!(chat.request_value().unwrap().get("user").is_none());
+        chat.set_user("user-1234".to_string());
+        chat.send().await.unwrap();
+        let sent: Value = serde_json::from_str(&mock.requests()[0].body).unwrap();
+        assert_eq!(sent["user"], "user-1234");
+        assert_eq!(chat.get_request().user(), Some("user-1234"));
+    }
+
+    #[test]
+    fn tool_call_arguments_parse_into_a_struct() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Arguments {
+            configuration: String,
+        }
+        let response: ChatResponse = serde_json::from_str(&tool_call_response(r#"{"configuration":"vlan 10"}"#)).unwrap();
+        let tool_call = response.all_tool_calls()[0];
+        assert_eq!(tool_call.parse_arguments::<Arguments>().unwrap(), Arguments { configuration: "vlan 10".to_string() });
+        assert_eq!(tool_call.arguments_value().unwrap(), serde_json::json!({ "configuration": "vlan 10" }));
+
+        let response: ChatResponse = serde_json::from_str(&tool_call_response(r#"{"config":"vlan 10"}"#)).unwrap();
+        assert!(response.all_tool_calls()[0].parse_arguments::<Arguments>().is_err());
+    }
+
+    #[tokio::test]
+    async fn with_api_key_sends_the_new_key_and_keeps_the_settings() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("one").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("two").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.set_temperature(0.3).unwrap();
+        chat.set_seed(7);
+        chat.set_header("x-tenant".to_string(), "a".to_string()).unwrap();
+        let tenant = chat.with_api_key("tenant-key".to_string());
+        chat.send().await.unwrap();
+        tenant.send().await.unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(requests[0].headers[AUTHORIZATION], "Bearer test-key");
+        assert_eq!(requests[1].headers[AUTHORIZATION], "Bearer tenant-key");
+        assert_eq!(requests[1].headers["x-tenant"], "a");
+        assert_eq!(requests[0].body, requests[1].body);
+        assert_eq!(tenant.get_request(), chat.get_request());
+    }
+
+    #[tokio::test]
+    async fn assistant_text_is_pushed_back_into_the_conversation() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("Hi there!").as_bytes()]);
+        let mut chat = chat(&mock);
+        let response = chat.send().await.unwrap();
+        assert_eq!(response.assistant_text().as_deref(), Some("Hi there!"));
+        chat.push_response(&response);
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].role, ChatRole::Assistant);
+        assert_eq!(messages[1].content.as_deref(), Some("Hi there!"));
+
+        let mut from_user = response.clone();
+        from_user.choices[0].message.role = ChatRole::User;
+        assert_eq!(from_user.assistant_text(), None);
+    }
+
+    #[test]
+    fn answered_without_tools_tells_tool_calls_from_direct_answers() {
+        let tool_call: ChatResponse = serde_json::from_str(&tool_call_response("{}")).unwrap();
+        assert!(!tool_call.answered_without_tools(&["lookup"]));
+
+        let direct = response_with_content("I know this one.");
+        assert!(direct.answered_without_tools(&["lookup"]));
+        assert!(!direct.answered_without_tools(&[]));
+
+        let mut truncated = direct.clone();
+        truncated.choices[0].finish_reason = Some("length".to_string());
+        assert!(!truncated.answered_without_tools(&["lookup"]));
+    }
+
+    #[test]
+    fn reasoning_formats_serialize_lowercase() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        assert!(chat.request_value().unwrap().get("reasoning_format").is_none());
+        for (format, expected) in [
+            (ReasoningFormat::Parsed, "parsed"),
+            (ReasoningFormat::Raw, "raw"),
+            (ReasoningFormat::Hidden, "hidden"),
+        ] {
+            assert_eq!(serde_json::to_value(&format).unwrap(), expected);
+            assert_eq!(format.to_string(), expected);
+            chat.set_reasoning_format(format.clone());
+            assert_eq!(chat.request_value().unwrap()["reasoning_format"], expected);
+            assert_eq!(serde_json::from_value::<ReasoningFormat>(expected.into()).unwrap(), format);
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_call_turn_is_rebuilt_in_the_history() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_call_response(r#"{"query":"rust"}"#).as_bytes()])
+            .respond(StatusCode::OK, vec![completion("Rust is a language.").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.add_tool(lookup_tool());
+        let response = chat.send().await.unwrap();
+        chat.append_assistant_tool_calls(&response);
+        let tool_calls: Vec<ToolCall> = response.all_tool_calls().into_iter().cloned().collect();
+        chat.add_tool_results(&tool_calls, vec![("call_1".to_string(), "a language".to_string())]);
+        let answer = chat.send().await.unwrap();
+        chat.append_assistant_tool_calls(&answer);
+        assert_eq!(chat.number_of_chat_messages(), 3);
+
+        let sent: Value = serde_json::from_str(&mock.requests()[1].body).unwrap();
+        assert_eq!(sent["messages"], serde_json::json!([
+            { "role": "user", "content": "hello" },
+            {
+                "role": "assistant",
+                "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "lookup", "arguments": "{\"query\":\"rust\"}" } }],
+            },
+            { "role": "tool", "content": "a language", "tool_call_id": "call_1" },
+        ]));
+    }
+
+    #[tokio::test]
+    async fn stream_to_string_concatenates_chunks_and_propagates_errors() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![sse(&[chunk("Hel"), chunk(""), chunk("lo")]).as_bytes()]);
+        assert_eq!(chat(&mock).stream_to_string().await.unwrap(), "Hello");
+
+        let error = r#"{"error":{"message":"overloaded","type":"server_error","param":null,"code":null}}"#;
+        let body = format!("data: {}\n\nevent: error\ndata: {}\n\n", chunk("partial"), error);
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        assert!(matches!(chat(&mock).stream_to_string().await, Err(GroqError::Api { .. })));
+
+        mock.respond(StatusCode::UNAUTHORIZED, vec![b"denied"]);
+        assert!(matches!(
+            chat(&mock).stream_to_string().await,
+            Err(GroqError::Status { status: StatusCode::UNAUTHORIZED, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_with_model_overrides_the_model_once() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("big").as_bytes()])
+            .respond(StatusCode::OK, vec![sse(&[chunk("big")]).as_bytes()])
+            .respond(StatusCode::OK, vec![completion("default").as_bytes()]);
+        let chat = chat(&mock);
+        chat.send_with_model("other-model").await.unwrap();
+        assert_eq!(chat.stream_with_model("other-model").await.unwrap().count().await, 1);
+        assert_eq!(chat.get_model(), "test-model");
+        chat.send().await.unwrap();
+
+        let models: Vec<Value> = mock.requests().iter()
+            .map(|request| serde_json::from_str::<Value>(&request.body).unwrap()["model"].clone())
+            .collect();
+        assert_eq!(models, vec!["other-model", "other-model", "test-model"]);
+    }
+
+    #[test]
+    fn usage_throughput_and_latency() {
+        let usage = ChatUsage {
+            queue_time: 0.25,
+            completion_tokens: 200,
+            completion_time: 0.5,
+            total_time: 0.75,
+            ..ChatUsage::default()
+        };
+        assert_eq!(usage.tokens_per_second(), Some(400.0));
+        assert_eq!(usage.total_latency(), 1.0);
+        assert_eq!(ChatUsage::default().tokens_per_second(), None);
+        assert_eq!(ChatUsage::default().total_latency(), 0.0);
+    }
+
+    #[test]
+    fn json_content_parses_into_a_struct() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Weather {
+            city: String,
+            celsius: i32,
+        }
+        let response = response_with_content(r#"{"city": "Oslo", "celsius": -3}"#);
+        assert_eq!(response.parse_json_content::<Weather>().unwrap(), Weather { city: "Oslo".to_string(), celsius: -3 });
+
+        let tool_call: ChatResponse = serde_json::from_str(&tool_call_response("{}")).unwrap();
+        let error = tool_call.parse_json_content::<Weather>().unwrap_err();
+        assert_eq!(error.to_string(), "response has no content");
+    }
+
+    #[tokio::test]
+    async fn log_summary_counts_without_exposing_content() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_calls_response(&["a", "b"]).as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.insert_chat_message(0, ChatMessage::new(ChatRole::System, "secret instructions", None));
+        let response = chat.send().await.unwrap();
+        chat.append_assistant_tool_calls(&response);
+
+        let summary = chat.log_summary();
+        assert_eq!(summary.message_count, 3);
+        assert_eq!(summary.total_tool_calls, 2);
+        assert_eq!(summary.model, "test-model");
+        assert_eq!(summary.estimated_tokens, chat.estimated_prompt_tokens());
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["role_counts"], serde_json::json!({ "system": 1, "user": 1, "assistant": 1 }));
+        assert!(!json.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_found_and_rejected() {
+        let mock = MockHttpClient::default();
+        let mut chat = chat(&mock);
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, &"x".repeat(41), None));
+        assert_eq!(chat.oversized_message_index(), None);
+        chat.set_max_message_tokens(10);
+        assert_eq!(chat.oversized_message_index(), Some(1));
+        match chat.send().await {
+            Err(GroqError::Validation(message)) => assert!(message.contains("Message 1"), "{}", message),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        assert!(mock.requests().is_empty());
+        chat.set_max_message_tokens(11);
+        assert_eq!(chat.oversized_message_index(), None);
+    }
+
+    #[test]
+    fn getters_return_what_the_setters_stored() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        assert_eq!(chat.get_seed(), None);
+        assert_eq!(chat.get_max_completion_tokens(), None);
+        assert_eq!(chat.get_service_tier(), None);
+        assert!(chat.get_response_format().is_none());
+
+        chat.set_model("other-model".to_string());
+        chat.set_temperature(0.5).unwrap();
+        chat.set_top_p(0.9).unwrap();
+        chat.set_frequency_penalty(0.25).unwrap();
+        chat.set_presence_penalty(-0.5).unwrap();
+        chat.set_parallel_tool_calls(false);
+        chat.set_seed(42);
+        chat.set_max_completion_tokens(256);
+        chat.set_service_tier(ChatServiceTier::Flex);
+        chat.set_response_format(ChatResponseFormat::JsonObject);
+        assert_eq!(chat.get_model(), "other-model");
+        assert_eq!(chat.get_temperature(), 0.5);
+        assert_eq!(chat.get_top_p(), 0.9);
+        assert_eq!(chat.get_frequency_penalty(), 0.25);
+        assert_eq!(chat.get_presence_penalty(), -0.5);
+        assert!(!chat.get_parallel_tool_calls());
+        assert_eq!(chat.get_seed(), Some(42));
+        assert_eq!(chat.get_max_completion_tokens(), Some(256));
+        assert_eq!(chat.get_service_tier(), Some(ChatServiceTier::Flex));
+        assert!(matches!(chat.get_response_format(), Some(ChatResponseFormat::JsonObject)));
+    }
+
+    #[test]
+    fn cleared_options_are_no_longer_sent() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_seed(42);
+        chat.set_response_format(ChatResponseFormat::JsonObject);
+        chat.set_service_tier(ChatServiceTier::Flex);
+        chat.set_frequency_penalty(1.0).unwrap();
+        chat.set_presence_penalty(-1.0).unwrap();
+        let request = chat.request_value().unwrap();
+        for key in ["seed", "response_format", "service_tier"] {
+            assert!(request.get(key).is_some(), "{} was not set", key);
+        }
+
+        chat.clear_seed();
+        chat.clear_response_format();
+        chat.clear_service_tier();
+        chat.reset_penalties();
+        let request = chat.request_value().unwrap();
+        for key in ["seed", "response_format", "service_tier"] {
+            assert!(request.get(key).is_none(), "{} was not cleared", key);
+        }
+        assert_eq!(request["frequency_penalty"], 0.0);
+        assert_eq!(request["presence_penalty"], 0.0);
+    }
+
+    fn response_finished_with(finish_reason: &str) -> ChatResponse {
+        let mut response = response_with_content("partial");
+        response.choices[0].finish_reason = Some(finish_reason.to_string());
+        response
+    }
+
+    #[test]
+    fn finish_reasons_flag_filtered_and_truncated_replies() {
+        let filtered = response_finished_with("content_filter");
+        assert!(filtered.was_filtered());
+        assert!(!filtered.was_truncated());
+        let truncated = response_finished_with("length");
+        assert!(truncated.was_truncated());
+        assert!(!truncated.was_filtered());
+        let stopped = response_finished_with("stop");
+        assert!(!stopped.was_filtered());
+        assert!(!stopped.was_truncated());
+    }
+
+    #[test]
+    fn reasoning_only_needs_truncated_reasoning_without_an_answer() {
+        let mut response = response_finished_with("length");
+        response.choices[0].message.reasoning = Some("Let me think about".to_string());
+        response.choices[0].message.content = Some("  ".to_string());
+        assert!(response.reasoning_only());
+
+        response.choices[0].message.content = None;
+        assert!(response.reasoning_only());
+
+        response.choices[0].message.content = Some("42".to_string());
+        assert!(!response.reasoning_only());
+
+        response.choices[0].message.content = None;
+        response.choices[0].finish_reason = Some("stop".to_string());
+        assert!(!response.reasoning_only());
+
+        assert!(!response_finished_with("length").reasoning_only());
+    }
+
+    #[test]
+    fn estimated_cost_uses_the_price_table() {
+        let usage = ChatUsage {
+            prompt_tokens: 2_000_000,
+            completion_tokens: 500_000,
+            ..ChatUsage::default()
+        };
+        let mut prices = PriceTable::new();
+        prices.insert("test-model", 0.05, 0.08);
+        assert_eq!(prices.get("test-model"), Some((0.05, 0.08)));
+        let cost = usage.estimated_cost("test-model", &prices).unwrap();
+        assert!((cost - 0.14).abs() < 1e-12, "{}", cost);
+        assert_eq!(usage.estimated_cost("unknown-model", &prices), None);
+    }
+
+    #[test]
+    fn tool_json_serializes_the_value_as_content() {
+        let message = ChatMessage::tool_json(serde_json::json!({ "temperature": 21.5, "unit": "celsius" }), "call_1".to_string());
+        assert_eq!(message.role, ChatRole::Tool);
+        assert_eq!(message.tool_call_id.as_deref(), Some("call_1"));
+        let content: Value = serde_json::from_str(message.content.as_deref().unwrap()).unwrap();
+        assert_eq!(content, serde_json::json!({ "temperature": 21.5, "unit": "celsius" }));
+        assert!(!message.content.unwrap().contains(' '));
+    }
+
+    #[test]
+    fn context_utilization_divides_total_tokens_by_the_window() {
+        let mut response = response_with_content("hi");
+        response.usage.as_mut().unwrap().total_tokens = 2048;
+        assert_eq!(response.context_utilization(8192), 0.25);
+        assert_eq!(response.context_utilization(0), 0.0);
+
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        assert_eq!(chat.context_utilization(&response), None);
+        let mut capability_table = CapabilityTable::new();
+        capability_table.insert("test-model", ModelCapabilities { context_window: Some(4096), ..Default::default() });
+        chat.set_capability_table(capability_table);
+        assert_eq!(chat.context_utilization(&response), Some(0.5));
+
+        response.usage = None;
+        assert_eq!(response.context_utilization(8192), 0.0);
+        assert_eq!(chat.context_utilization(&response), Some(0.0));
+    }
+
+    #[test]
+    fn system_prompt_is_read_from_a_file() {
+        let path = env::temp_dir().join(format!("groq-client-rs-system-{}.txt", std::process::id()));
+        fs::write(&path, "You are terse.").unwrap();
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.system_from_file(&path).unwrap();
+        chat.system_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let messages = chat.get_chat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, ChatRole::System);
+        assert_eq!(messages[0].content.as_deref(), Some("You are terse."));
+
+        match chat.system_from_file(&path) {
+            Err(GroqError::Io(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::NotFound);
+                assert!(e.to_string().contains("groq-client-rs-system"), "{}", e);
+            }
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+        assert_eq!(chat.number_of_chat_messages(), 2);
+    }
+
+    #[test]
+    fn json_schema_response_format_is_nested_under_json_schema() {
+        let schema = serde_json::json!({ "type": "object", "properties": { "answer": { "type": "string" } } });
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_response_format(ChatResponseFormat::JsonSchema { name: "answer".to_string(), schema: schema.clone(), strict: true });
+        let expected = serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "answer", "schema": schema, "strict": true },
+        });
+        assert_eq!(chat.request_value().unwrap()["response_format"], expected);
+
+        match serde_json::from_value(expected).unwrap() {
+            ChatResponseFormat::JsonSchema { name, strict, .. } => {
+                assert_eq!(name, "answer");
+                assert!(strict);
+            }
+            other => panic!("expected a JSON schema format, got {:?}", other),
+        }
+        assert_eq!(serde_json::to_value(ChatResponseFormat::JsonObject).unwrap(), serde_json::json!({ "type": "json_object" }));
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[test]
+    fn tool_arguments_are_checked_against_the_schema() {
+        let tool = lookup_tool();
+        assert!(tool.validate_arguments(&serde_json::json!({ "query": "rust" })).is_ok());
+        match tool.validate_arguments(&serde_json::json!({ "query": 7 })) {
+            Err(GroqError::InvalidToolArguments { tool, errors }) => {
+                assert_eq!(tool, "lookup");
+                assert_eq!(errors.len(), 1);
+                assert!(errors[0].contains("/query"), "{}", errors[0]);
+            }
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+        assert!(matches!(
+            tool.validate_arguments(&serde_json::json!({})),
+            Err(GroqError::InvalidToolArguments { .. })
+        ));
+    }
+
+    #[cfg(feature = "jsonschema")]
+    #[tokio::test]
+    async fn invalid_tool_arguments_go_back_to_the_model() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![tool_call_response(r#"{"query":7}"#).as_bytes()])
+            .respond(StatusCode::OK, vec![completion("done").as_bytes()]);
+        let mut chat = chat(&mock);
+        chat.add_tool(lookup_tool());
+        let dispatched = Arc::new(Mutex::new(0));
+        let counter = dispatched.clone();
+        let response = chat.run_with_tools(move |_, _| {
+            *counter.lock().unwrap() += 1;
+            async { Ok("found".to_string()) }
+        }, 2).await.unwrap();
+        assert_eq!(response.content(), Some("done"));
+        assert_eq!(*dispatched.lock().unwrap(), 0);
+        let tool_result = chat.get_chat_messages()[2].content.clone().unwrap();
+        assert!(tool_result.starts_with("Invalid arguments for tool lookup"), "{}", tool_result);
+    }
+
+    #[test]
+    fn roles_parse_from_their_wire_names() {
+        for (name, role) in [
+            ("user", ChatRole::User),
+            ("assistant", ChatRole::Assistant),
+            ("system", ChatRole::System),
+            ("tool", ChatRole::Tool),
+        ] {
+            assert_eq!(name.parse::<ChatRole>().unwrap(), role);
+            assert_eq!(ChatRole::try_from(name).unwrap(), role);
+            assert_eq!(serde_json::to_value(&role).unwrap(), name);
+        }
+        assert_eq!("developer".parse::<ChatRole>().unwrap_err(), "Unknown chat role: developer");
+        assert!(ChatRole::try_from("User").is_err());
+    }
+
+    #[test]
+    fn request_json_matches_the_snapshot() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::System, "Be brief.", None));
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.set_temperature(0.5).unwrap();
+        chat.set_max_completion_tokens(64);
+        chat.set_seed(7);
+        let expected = concat!(
+            r#"{"frequency_penalty":0.0,"max_completion_tokens":64,"#,
+            r#""messages":[{"content":"Be brief.","role":"system"},{"content":"hello","role":"user"}],"#,
+            r#""model":"test-model","presence_penalty":0.0,"seed":7,"stream":false,"temperature":0.5,"top_p":1.0}"#,
+        );
+        assert_eq!(chat.request_json().unwrap(), expected);
+        assert_eq!(chat.request_value().unwrap(), serde_json::from_str::<Value>(expected).unwrap());
+    }
+
+    #[tokio::test]
+    async fn hooks_fire_once_per_send() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("one").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("two").as_bytes()])
+            .respond(StatusCode::BAD_REQUEST, vec![b"bad request"])
+            .respond(StatusCode::OK, vec![sse(&[chunk("a"), chunk("b")]).as_bytes()]);
+        let mut chat = chat(&mock);
+        let requests = Arc::new(Mutex::new(0));
+        let responses = Arc::new(Mutex::new(vec![]));
+        let request_counter = requests.clone();
+        chat.on_request(Box::new(move |_| *request_counter.lock().unwrap() += 1));
+        let response_recorder = responses.clone();
+        chat.on_response(Box::new(move |response| {
+            response_recorder.lock().unwrap().push(response.content().unwrap_or_default().to_string())
+        }));
+
+        chat.send().await.unwrap();
+        chat.send().await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 2);
+        assert_eq!(*responses.lock().unwrap(), vec!["one", "two"]);
+
+        assert!(chat.send().await.is_err());
+        assert_eq!(*requests.lock().unwrap(), 3);
+        assert_eq!(responses.lock().unwrap().len(), 2);
+
+        chat.stream_to_string().await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 4);
+        assert_eq!(*responses.lock().unwrap(), vec!["one", "two", "a", "b"]);
+    }
+
+    #[test]
+    fn tool_call_only_message_has_no_content_key() {
+        let response: ChatResponse = serde_json::from_str(&tool_call_response("{}")).unwrap();
+        let message = response.choices[0].to_message();
+        assert!(message.content.is_none());
+        let json = serde_json::to_value(&message).unwrap();
+        assert!(json.get("content").is_none());
+        assert_eq!(json["tool_calls"][0]["id"], "call_1");
+        assert!(!serde_json::to_string(&message).unwrap().contains("null"));
+    }
+
+    #[tokio::test]
+    async fn text_stream_yields_only_text_pieces() {
+        let mock = MockHttpClient::default();
+        let mut role_only: Value = serde_json::from_str(&chunk("")).unwrap();
+        role_only["choices"][0]["delta"] = serde_json::json!({ "role": "assistant" });
+        let error = r#"{"error":{"message":"overloaded","type":"server_error","param":null,"code":null}}"#;
+        let body = sse(&[role_only.to_string(), chunk("Hel"), chunk(""), chunk("lo")])
+            .replace("data: [DONE]\n\n", &format!("event: error\ndata: {}\n\n", error));
+        mock.respond(StatusCode::OK, vec![body.as_bytes()]);
+        let items: Vec<_> = chat(&mock).text_stream().await.unwrap().collect().await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_deref().unwrap(), "Hel");
+        assert_eq!(items[1].as_deref().unwrap(), "lo");
+        assert!(matches!(items[2], Err(GroqError::Api { .. })));
+    }
+
+    #[test]
+    fn tool_calls_are_gathered_across_choices() {
+        let mut response: Value = serde_json::from_str(&tool_calls_response(&["a", "b"])).unwrap();
+        let mut second = response["choices"][0].clone();
+        second["index"] = 1.into();
+        second["message"]["tool_calls"] = serde_json::json!([
+            { "id": "call_3", "type": "function", "function": { "name": "c", "arguments": "{}" } },
+        ]);
+        response["choices"].as_array_mut().unwrap().push(second);
+        let response: ChatResponse = serde_json::from_value(response).unwrap();
+        let ids: Vec<&str> = response.all_tool_calls().iter().map(|tool_call| tool_call.id.as_str()).collect();
+        assert_eq!(ids, vec!["call_1", "call_2", "call_3"]);
+        assert!(response.has_tool_calls());
+
+        let answer = response_with_content("no tools");
+        assert!(answer.all_tool_calls().is_empty());
+        assert!(!answer.has_tool_calls());
+    }
+
+    #[test]
+    fn tool_choice_constructors_serialize_to_the_wire_format() {
+        assert_eq!(
+            serde_json::to_string(&ToolChoice::function("foo")).unwrap(),
+            r#"{"type":"function","function":{"name":"foo"}}"#,
+        );
+        assert_eq!(serde_json::to_value(ToolChoice::auto()).unwrap(), "auto");
+        assert_eq!(serde_json::to_value(ToolChoice::none()).unwrap(), "none");
+        assert_eq!(serde_json::to_value(ToolChoice::required()).unwrap(), "required");
+    }
+
+    #[tokio::test]
+    async fn is_chunk_tells_streamed_chunks_from_completions() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("full").as_bytes()])
+            .respond(StatusCode::OK, vec![sse(&[chunk("part")]).as_bytes()]);
+        let chat = chat(&mock);
+        let response = chat.send().await.unwrap();
+        assert_eq!(response.object, ObjectType::ChatCompletion);
+        assert!(!response.is_chunk());
+        let chunks: Vec<ChatResponse> = chat.stream().await.unwrap().map(Result::unwrap).collect().await;
+        assert_eq!(chunks[0].object, ObjectType::ChatCompletionChunk);
+        assert!(chunks[0].is_chunk());
+    }
+
+    /// Accepts one connection, answers it with `completion(content)` and
+    /// returns the request head it received.
+    #[cfg(feature = "reqwest")]
+    async fn serve_once(listener: tokio::net::TcpListener, content: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 4096];
+        let head = loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            received.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&received).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let content_length: usize = head.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                if body.len() >= content_length {
+                    break head.to_string();
+                }
+            }
+        };
+        let body = completion(content);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+        head
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn programmatic_proxy_is_used_without_the_env_var() {
+        assert!(env::var("HTTPS_PROXY").is_err());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve_once(listener, "via proxy").await });
+
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.set_api_url("http://api.example.invalid/chat/completions".to_string());
+        chat.set_proxy(proxy).unwrap();
+        chat.set_proxy_auth("user".to_string(), "secret".to_string());
+        let response = chat.send().await.unwrap();
+        assert_eq!(response.content(), Some("via proxy"));
+
+        let head = server.await.unwrap();
+        assert!(head.starts_with("POST http://api.example.invalid/chat/completions HTTP/1.1"), "{}", head);
+        // base64 of "user:secret"
+        assert!(head.to_ascii_lowercase().contains("proxy-authorization: basic dxnlcjpzzwnyzxq="), "{}", head);
+        assert!(chat.set_proxy("not a url".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn ask_grows_the_history_and_rolls_back_on_failure() {
+        let mock = MockHttpClient::default();
+        mock.respond(StatusCode::OK, vec![completion("Hi!").as_bytes()])
+            .respond(StatusCode::OK, vec![completion("Fine.").as_bytes()])
+            .respond(StatusCode::BAD_REQUEST, vec![b"bad request"]);
+        let mut chat = chat(&mock);
+        assert_eq!(chat.ask("hi").await.unwrap(), "Hi!");
+        assert_eq!(chat.ask("how are you?").await.unwrap(), "Fine.");
+        let roles: Vec<ChatRole> = chat.get_chat_messages().into_iter().map(|message| message.role).collect();
+        assert_eq!(roles, vec![ChatRole::User, ChatRole::User, ChatRole::Assistant, ChatRole::User, ChatRole::Assistant]);
+
+        assert!(chat.ask("and now?").await.is_err());
+        assert_eq!(chat.number_of_chat_messages(), 5);
+        assert_eq!(chat.get_chat_messages()[4].content.as_deref(), Some("Fine."));
+        let sent: Value = serde_json::from_str(&mock.requests()[2].body).unwrap();
+        assert_eq!(sent["messages"].as_array().unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn role_only_first_chunk_parses_without_content() {
+        let mock = MockHttpClient::default();
+        let mut role_only: Value = serde_json::from_str(&chunk("")).unwrap();
+        role_only["choices"][0]["delta"] = serde_json::json!({ "role": "assistant" });
+        mock.respond(StatusCode::OK, vec![sse(&[role_only.to_string(), chunk("hi")]).as_bytes()]);
+        let chunks: Vec<ChatResponse> = chat(&mock).stream().await.unwrap().map(Result::unwrap).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].content(), None);
+        assert_eq!(chunks[0].choices[0].message.role, ChatRole::Assistant);
+        assert_eq!(chunks[1].content(), Some("hi"));
+    }
+
+    /// A self-signed CA certificate, only ever parsed.
+    #[cfg(feature = "reqwest")]
+    const TEST_CA_PEM: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBmDCCAT+gAwIBAgIUZG+OJQ/vN/mWO2s8/6vJ/33MKTYwCgYIKoZIzj0EAwIw
+ITEfMB0GA1UEAwwWZ3JvcS1jbGllbnQtcnMgdGVzdCBDQTAgFw0yNjEwMTYxMzI5
+MjBaGA8yMTI2MDkyMjEzMjkyMFowITEfMB0GA1UEAwwWZ3JvcS1jbGllbnQtcnMg
+dGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABJD4xu4NqxETp+laVzoK
+fZrrfaX1JU7xcYGtIXOPxW7fmAPyn6fKjS2TYaCwff61nuAFYzGwxPf0LnmWk1a/
+mnWjUzBRMB0GA1UdDgQWBBT732AHmxDkywQpMEeQl6WA6K6U4DAfBgNVHSMEGDAW
+gBT732AHmxDkywQpMEeQl6WA6K6U4DAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49
+BAMCA0cAMEQCIAdEe7nTO7R5ZY9780MwaB3MkXBh677IOutTNlMivA21AiA/o08E
+zBhIMaKVqcrs/DKOjtU8dkD8aXbkpi9Kxs30PA==
+-----END CERTIFICATE-----
+";
+
+    #[cfg(feature = "reqwest")]
+    #[tokio::test]
+    async fn client_is_built_with_extra_root_certificates() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/chat/completions", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { serve_once(listener, "trusted").await });
+
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "hello", None));
+        chat.set_api_url(api_url);
+        chat.add_root_certificate(reqwest::Certificate::from_pem(TEST_CA_PEM.as_bytes()).unwrap());
+        chat.set_danger_accept_invalid_certs(true);
+        assert_eq!(chat.reqwest_config.root_certificates.len(), 1);
+        assert!(chat.reqwest_config.danger_accept_invalid_certs);
+        assert_eq!(chat.send().await.unwrap().content(), Some("trusted"));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn messages_are_inserted_at_a_clamped_index() {
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "first", None));
+        chat.add_chat_message(ChatMessage::new(ChatRole::User, "last", None));
+        chat.insert_chat_message(0, ChatMessage::new(ChatRole::System, "system", None));
+        chat.insert_chat_message(2, ChatMessage::new(ChatRole::System, "reminder", None));
+        chat.insert_chat_message(100, ChatMessage::new(ChatRole::Assistant, "appended", None));
+        let contents: Vec<String> = chat.get_chat_messages().into_iter().filter_map(|message| message.content).collect();
+        assert_eq!(contents, vec!["system", "first", "reminder", "last", "appended"]);
+
+        chat.replace_chat_message(4, ChatMessage::new(ChatRole::Assistant, "replaced", None)).unwrap();
+        assert_eq!(chat.get_chat_messages()[4].content.as_deref(), Some("replaced"));
+        assert_eq!(
+            chat.replace_chat_message(5, ChatMessage::new(ChatRole::User, "nowhere", None)).unwrap_err(),
+            "Message index 5 out of range for 5 messages",
+        );
+        assert_eq!(chat.number_of_chat_messages(), 5);
+    }
+
+    /// Records the fields of every `groq.send` span, including those recorded
+    /// after the span was created.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SendSpanFields(Arc<Mutex<BTreeMap<String, String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for SendSpanFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0.lock().unwrap().insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for SendSpanFields
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(&self, span: &tracing::span::Attributes<'_>, _: &tracing::span::Id, _: tracing_subscriber::layer::Context<'_, S>) {
+            if span.metadata().name() == "groq.send" {
+                span.record(&mut self.clone());
+            }
+        }
+
+        fn on_record(&self, id: &tracing::span::Id, values: &tracing::span::Record<'_>, context: tracing_subscriber::layer::Context<'_, S>) {
+            if context.metadata(id).is_some_and(|metadata| metadata.name() == "groq.send") {
+                values.record(&mut self.clone());
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn send_span_records_the_usage() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let fields = SendSpanFields::default();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(fields.clone()));
+        let mock = MockHttpClient::default();
+        let mut reply: Value = serde_json::from_str(&completion("hi")).unwrap();
+        reply["usage"]["prompt_tokens"] = 12.into();
+        reply["usage"]["completion_tokens"] = 34.into();
+        reply["usage"]["total_time"] = 0.5.into();
+        mock.respond(StatusCode::OK, vec![reply.to_string().as_bytes()]);
+        chat(&mock).send().await.unwrap();
+
+        let expected: BTreeMap<String, String> = [
+            ("model", "test-model"),
+            ("prompt_tokens", "12"),
+            ("completion_tokens", "34"),
+            ("total_time", "0.5"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        assert_eq!(*fields.0.lock().unwrap(), expected);
+    }
+
+    fn two_choice_response() -> ChatResponse {
+        let mut response: Value = serde_json::from_str(&completion("first")).unwrap();
+        let mut second = response["choices"][0].clone();
+        second["index"] = 1.into();
+        second["message"]["content"] = "second".into();
+        response["choices"].as_array_mut().unwrap().push(second);
+        serde_json::from_value(response).unwrap()
+    }
+
+    #[test]
+    fn messages_iterate_over_every_choice() {
+        let response = two_choice_response();
+        let contents: Vec<Option<&str>> = response.messages().map(|message| message.content.as_deref()).collect();
+        assert_eq!(contents, vec![Some("first"), Some("second")]);
+        let owned: Vec<ChatMessage> = response.into_messages().collect();
+        assert_eq!(owned.len(), 2);
+        assert!(owned.iter().all(|message| message.role == ChatRole::Assistant));
+        assert_eq!(owned[1].content.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn token_accessors_tolerate_missing_usage() {
+        let mut response = response_with_content("hi");
+        let usage = response.usage.as_mut().unwrap();
+        usage.prompt_tokens = 10;
+        usage.completion_tokens = 5;
+        usage.total_tokens = 15;
+        assert_eq!(response.prompt_tokens(), Some(10));
+        assert_eq!(response.completion_tokens(), Some(5));
+        assert_eq!(response.total_tokens(), Some(15));
+
+        response.usage = None;
+        assert_eq!(response.prompt_tokens(), None);
+        assert_eq!(response.completion_tokens(), None);
+        assert_eq!(response.total_tokens(), None);
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn tool_parameters_are_derived_from_the_arguments_type() {
+        #[derive(schemars::JsonSchema, Deserialize)]
+        #[allow(dead_code)]
+        struct WeatherArguments {
+            /// The city to look up.
+            city: String,
+            days: Option<u8>,
+        }
+        let tool = Tool::from_schema::<WeatherArguments>("weather", "Looks up the weather");
+        assert_eq!(tool.function.name.as_deref(), Some("weather"));
+        assert_eq!(tool.function.description.as_deref(), Some("Looks up the weather"));
+        let parameters = tool.function.parameters.unwrap();
+        assert!(parameters.get("$schema").is_none());
+        assert_eq!(parameters["type"], "object");
+        assert_eq!(parameters["required"], serde_json::json!(["city"]));
+        assert_eq!(parameters["properties"]["city"], serde_json::json!({ "type": "string", "description": "The city to look up." }));
+        assert_eq!(parameters["properties"]["days"]["type"], serde_json::json!(["integer", "null"]));
+    }
+
+    #[test]
+    fn queue_and_generation_latency_convert_seconds() {
+        let usage = ChatUsage {
+            queue_time: 0.125,
+            prompt_time: 0.25,
+            completion_time: 1.5,
+            ..ChatUsage::default()
+        };
+        assert_eq!(usage.queue_latency(), Duration::from_millis(125));
+        assert_eq!(usage.generation_latency(), Duration::from_millis(1750));
+
+        let negative = ChatUsage { queue_time: -1.0, ..ChatUsage::default() };
+        assert_eq!(negative.queue_latency(), Duration::ZERO);
+        assert_eq!(ChatUsage::default().generation_latency(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,100 @@
+use std::{io, string::FromUtf8Error, time::Duration};
+
+use http::StatusCode;
+use thiserror::Error;
+
+use crate::chat::{ChatError, ChatMessage};
+
+#[derive(Debug, Error)]
+pub enum GroqError {
+    #[cfg(feature = "reqwest")]
+    #[error("HTTP error: {0}")]
+    Http(reqwest::Error),
+    #[error("{error}")]
+    Api {
+        /// The HTTP status of the response, or `None` for errors reported
+        /// inside an already established stream.
+        status: Option<StatusCode>,
+        error: ChatError,
+    },
+    /// An error status whose body is not an API error, such as a gateway's
+    /// HTML page. The raw body is kept.
+    #[error("Request failed with status {status}: {body}")]
+    Status { status: StatusCode, body: String },
+    #[error("Failed to decode response: {source}")]
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+    #[error("Failed to encode request: {0}")]
+    Encode(#[from] serde_json::Error),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Invalid UTF-8 in response: {0}")]
+    InvalidUtf8(#[from] FromUtf8Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Request blocked by content moderation: {0}")]
+    Moderation(String),
+    #[error("Invalid request: {0}")]
+    Validation(String),
+    #[error("Invalid arguments for tool {tool}: {}", errors.join("; "))]
+    InvalidToolArguments {
+        tool: String,
+        errors: Vec<String>,
+    },
+    #[error("Tool calls did not finish within {max_iterations} iterations")]
+    MaxToolIterationsExceeded {
+        max_iterations: usize,
+        /// The conversation at the time the cap was hit.
+        messages: Vec<ChatMessage>,
+    },
+    #[error("Model not found: {0}")]
+    ModelNotFound(String),
+    #[error("No content received within {0:?}")]
+    FirstTokenTimeout(Duration),
+    #[error("Stream exceeded the limit of {0} chunks")]
+    StreamChunkLimit(usize),
+    #[error("Stream interrupted after {} bytes of content: {source}", partial.len())]
+    StreamInterrupted {
+        partial: String,
+        source: Box<GroqError>,
+    },
+    #[error("Stream ended without returning any chunks")]
+    EmptyStream,
+}
+
+impl GroqError {
+    /// The HTTP status of a failed response, whether or not its body was an
+    /// API error.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            GroqError::Api { status, .. } => *status,
+            GroqError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the failure is likely to go away when the request is retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            #[cfg(feature = "reqwest")]
+            GroqError::Http(e) => e.is_connect() || e.is_request() || e.is_body(),
+            GroqError::Timeout | GroqError::FirstTokenTimeout(_) | GroqError::Io(_) => true,
+            GroqError::Status { status, .. } => status.is_server_error(),
+            GroqError::Api { status: Some(status), .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl From<reqwest::Error> for GroqError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            GroqError::Timeout
+        } else {
+            GroqError::Http(e)
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::{future::Future, io, pin::Pin};
+#[cfg(feature = "reqwest")]
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use http::{HeaderMap, StatusCode};
+
+use crate::chat::{BoxStreamUnpin, BoxUnpinExt};
+use crate::error::GroqError;
+
+/// `Send` on native targets. On WebAssembly, where HTTP futures and bodies are
+/// not `Send`, every type qualifies.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` on native targets, see `MaybeSend`.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSync: Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSync {}
+#[cfg(target_arch = "wasm32")]
+impl<T: ?Sized> MaybeSync for T {}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type HttpFuture<'a> = Pin<Box<dyn Future<Output = Result<HttpResponse, GroqError>> + Send + 'a>>;
+#[cfg(target_arch = "wasm32")]
+pub type HttpFuture<'a> = Pin<Box<dyn Future<Output = Result<HttpResponse, GroqError>> + 'a>>;
+
+/// The transport used to reach the API. Implement it to route requests through
+/// another HTTP stack, such as `fetch` on WebAssembly, or to serve canned
+/// responses in tests.
+///
+/// On native targets transports must be `Send + Sync` and return `Send`
+/// futures, since `Chat` is shared across tasks. On WebAssembly these bounds
+/// are dropped; build the crate without the default `reqwest` feature there.
+pub trait HttpClient: MaybeSend + MaybeSync {
+    fn post(&self, request: HttpRequest) -> HttpFuture<'_>;
+
+    /// Sends a GET request; its `body` is empty. Transports that only serve
+    /// chat completions can keep the default, which fails.
+    fn get(&self, request: HttpRequest) -> HttpFuture<'_> {
+        Box::pin(async move {
+            Err(GroqError::Validation(format!("This HTTP client does not support GET requests to {}", request.url)))
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BoxStreamUnpin<Result<Bytes, io::Error>>,
+}
+
+impl HttpResponse {
+    /// Builds a response whose body arrives as a single chunk.
+    pub fn from_bytes(status: StatusCode, body: impl Into<Bytes>) -> Self {
+        let body: Bytes = body.into();
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: futures::stream::once(async move { Ok(body) }).boxed_unpin(),
+        }
+    }
+
+    #[cfg(feature = "reqwest")]
+    fn from_reqwest(response: reqwest::Response) -> Self {
+        Self {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: response.bytes_stream().map_err(io::Error::other).boxed_unpin(),
+        }
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>, GroqError> {
+        let chunks: Vec<Bytes> = self.body.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    pub async fn text(self) -> Result<String, GroqError> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).into_owned())
+    }
+}
+
+/// Settings of the default transport, see the proxy, certificate and timeout
+/// setters of `Chat`.
+#[cfg(feature = "reqwest")]
+#[derive(Clone, Default)]
+pub(crate) struct ReqwestConfig {
+    pub(crate) proxy: Option<String>,
+    pub(crate) proxy_auth: Option<(String, String)>,
+    pub(crate) root_certificates: Vec<reqwest::Certificate>,
+    pub(crate) danger_accept_invalid_certs: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) overall_timeout: Option<Duration>,
+}
+
+#[cfg(feature = "reqwest")]
+impl ReqwestConfig {
+    /// Builds a client with these settings. Without an explicit proxy the
+    /// `HTTPS_PROXY` environment variable is used.
+    pub(crate) fn build(&self) -> Result<ReqwestHttpClient, GroqError> {
+        let mut client_builder = reqwest::Client::builder();
+        if let Some(proxy) = self.proxy.clone().or_else(|| std::env::var("HTTPS_PROXY").ok()) {
+            let mut proxy = reqwest::Proxy::all(proxy)?;
+            if let Some((username, password)) = &self.proxy_auth {
+                proxy = proxy.basic_auth(username, password);
+            }
+            client_builder = client_builder.proxy(proxy);
+        }
+        for certificate in &self.root_certificates {
+            client_builder = client_builder.add_root_certificate(certificate.clone());
+        }
+        if self.danger_accept_invalid_certs {
+            client_builder = client_builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(connect_timeout);
+        }
+        if let Some(overall_timeout) = self.overall_timeout {
+            client_builder = client_builder.timeout(overall_timeout);
+        }
+        Ok(ReqwestHttpClient::new(client_builder.build()?))
+    }
+}
+
+/// The default transport, backed by a `reqwest::Client`.
+#[cfg(feature = "reqwest")]
+#[derive(Clone, Debug)]
+pub struct ReqwestHttpClient {
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "reqwest")]
+impl ReqwestHttpClient {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpClient for ReqwestHttpClient {
+    fn post(&self, request: HttpRequest) -> HttpFuture<'_> {
+        Box::pin(async move {
+            let response = self.client
+                .post(request.url)
+                .headers(request.headers)
+                .body(request.body)
+                .send()
+                .await?;
+            Ok(HttpResponse::from_reqwest(response))
+        })
+    }
+
+    fn get(&self, request: HttpRequest) -> HttpFuture<'_> {
+        Box::pin(async move {
+            let response = self.client
+                .get(request.url)
+                .headers(request.headers)
+                .send()
+                .await?;
+            Ok(HttpResponse::from_reqwest(response))
+        })
+    }
+}
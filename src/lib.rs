@@ -1 +1,4 @@
 pub mod chat;
+pub mod error;
+pub mod http;
+pub mod models;
@@ -0,0 +1,95 @@
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{api_error, decode, Chat};
+use crate::error::GroqError;
+
+/// Access to the `/models` endpoints.
+pub struct Models;
+
+impl Models {
+    /// Fetches the metadata of the model `model_id`, using the base URL,
+    /// credentials, headers and transport of `chat`. A model the API does not
+    /// know is reported as `GroqError::ModelNotFound`.
+    pub async fn retrieve(chat: &Chat, model_id: &str) -> Result<ModelInfo, GroqError> {
+        let response = chat.get(&["models", model_id]).await?;
+        let status = response.status;
+        let raw_response = response.text().await?;
+        if status == StatusCode::NOT_FOUND {
+            return Err(GroqError::ModelNotFound(model_id.to_string()));
+        }
+        if status.is_client_error() || status.is_server_error() {
+            return Err(api_error(status, &raw_response));
+        }
+        decode::<ModelInfo>(&raw_response)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ModelInfo {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub owned_by: String,
+    #[serde(default)]
+    pub active: Option<bool>,
+    /// The maximum number of tokens the model can attend to.
+    #[serde(default)]
+    pub context_window: Option<u32>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::http::{HttpClient, HttpFuture, HttpRequest, HttpResponse};
+
+    /// Answers every GET with the same canned response and records the URLs.
+    #[derive(Clone)]
+    struct MockHttpClient {
+        status: StatusCode,
+        body: &'static str,
+        urls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl HttpClient for MockHttpClient {
+        fn post(&self, _request: HttpRequest) -> HttpFuture<'_> {
+            unreachable!("models only issue GET requests")
+        }
+
+        fn get(&self, request: HttpRequest) -> HttpFuture<'_> {
+            self.urls.lock().unwrap().push(request.url);
+            Box::pin(async move { Ok(HttpResponse::from_bytes(self.status, self.body)) })
+        }
+    }
+
+    fn chat(status: StatusCode, body: &'static str) -> (Chat, Arc<Mutex<Vec<String>>>) {
+        let urls = Arc::new(Mutex::new(vec![]));
+        let mut chat = Chat::new("test-key".to_string(), "test-model".to_string());
+        chat.set_base_url("https://example.test/openai/v1/".to_string());
+        let chat = chat.with_http_client(MockHttpClient { status, body, urls: urls.clone() });
+        (chat, urls)
+    }
+
+    #[tokio::test]
+    async fn retrieve_decodes_a_model_under_the_chat_base_url() {
+        let body = r#"{"id":"llama/3 8b","object":"model","created":1,"owned_by":"Meta","active":true,"context_window":8192}"#;
+        let (chat, urls) = chat(StatusCode::OK, body);
+        let model = Models::retrieve(&chat, "llama/3 8b").await.unwrap();
+        assert_eq!(model.id, "llama/3 8b");
+        assert_eq!(model.context_window, Some(8192));
+        assert_eq!(model.max_completion_tokens, None);
+        assert_eq!(urls.lock().unwrap()[0], "https://example.test/openai/v1/models/llama%2F3%208b");
+    }
+
+    #[tokio::test]
+    async fn retrieve_maps_404_to_model_not_found() {
+        let body = r#"{"error":{"message":"The model does not exist","type":"invalid_request_error","param":null,"code":"model_not_found"}}"#;
+        let (chat, _) = chat(StatusCode::NOT_FOUND, body);
+        let result = Models::retrieve(&chat, "missing").await;
+        assert!(matches!(result, Err(GroqError::ModelNotFound(model)) if model == "missing"));
+    }
+}